@@ -0,0 +1,416 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::store::{
+    FileKeyStore, FileStoreOptions, KeyStore, MemoryKeyStore, Partitions, Region, SqliteKeyStore,
+    DAYS_WINDOW,
+};
+
+/// The configuration of the backend service.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// The address the API is served on unless a socket is passed in.
+    pub listen: SocketAddr,
+    /// The folder the daily tracing key store lives in.
+    pub db_path: PathBuf,
+    /// The storage backend to use.
+    pub backend: StoreBackend,
+    /// Fsync the file backend after every write.
+    pub fsync: bool,
+    /// Opens the store read-only.
+    ///
+    /// Submissions are rejected and retention does not run, so this is
+    /// meant for instances that serve fetches next to a writable instance.
+    pub read_only: bool,
+    /// The maximum number of keys returned per page by cursor fetches.
+    pub fetch_page_size: usize,
+    /// Controls the caching headers of fetch responses.
+    pub cache: CacheConfig,
+    /// Controls how long keys are kept.
+    pub retention: RetentionConfig,
+    /// Enables the key export endpoint if configured.
+    pub export: Option<ExportConfig>,
+    /// Controls replication between instances.
+    pub replication: ReplicationConfig,
+    /// Enables the exchange of keys with other regions if configured.
+    pub federation: Option<FederationConfig>,
+    /// Controls rate and request size limits.
+    pub limits: LimitsConfig,
+    /// Serves HTTPS instead of HTTP if configured.
+    pub tls: Option<TlsConfig>,
+    /// A shared secret that grants access to the admin endpoints.
+    ///
+    /// It is sent as `Authorization: Bearer <token>`.  Without it or a
+    /// client certificate authority in `tls` the admin endpoints are
+    /// closed.
+    pub admin_token: Option<String>,
+}
+
+/// The available storage backends.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    /// One bucket file per day in `db_path`.
+    #[default]
+    File,
+    /// Keeps all keys in memory.  Nothing is persisted.
+    Memory,
+    /// An SQLite database at `db_path/keys.sqlite`.
+    Sqlite,
+}
+
+impl FromStr for StoreBackend {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<StoreBackend, io::Error> {
+        match s {
+            "file" => Ok(StoreBackend::File),
+            "memory" => Ok(StoreBackend::Memory),
+            "sqlite" => Ok(StoreBackend::Sqlite),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown storage backend",
+            )),
+        }
+    }
+}
+
+/// Configures the `Cache-Control` headers of fetch responses.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// The max age in seconds for responses that include the current day.
+    pub open_max_age: u64,
+    /// The max age in seconds for responses that only cover past days.
+    ///
+    /// Keys for past days can still be submitted late, so this controls how
+    /// long it might take for such keys to reach clients behind a CDN.
+    pub closed_max_age: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            open_max_age: 60,
+            closed_max_age: 86400,
+        }
+    }
+}
+
+/// Configures TLS termination.
+///
+/// The certificate and keys are reloaded on `SIGHUP`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// Path to the PEM encoded certificate chain.
+    pub cert_path: PathBuf,
+    /// Path to the PEM encoded private key (PKCS#8 or RSA).
+    pub key_path: PathBuf,
+    /// Path to the PEM encoded certificate authorities of clients.
+    ///
+    /// If set, a client certificate signed by one of them grants access to
+    /// the admin, replication and federation endpoints.  Requests to peers and to the
+    /// replication primary then present the certificate of this server and
+    /// trust these authorities in addition to the public ones.
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// Configures rate and request size limits.
+///
+/// Rate limits are disabled unless configured.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// The maximum size of submission bodies in bytes.
+    pub max_body_size: u64,
+    /// Limits submissions per client IP.
+    pub submit_per_ip: Option<RateConfig>,
+    /// Limits submissions per upload token (the `X-Upload-Token` header).
+    pub submit_per_token: Option<RateConfig>,
+    /// Limits fetches per client IP.
+    pub fetch_per_ip: Option<RateConfig>,
+    /// The maximum number of clients that are tracked per limit.
+    pub max_clients: usize,
+    /// Takes the client IP from the last entry of the `X-Forwarded-For`
+    /// header.
+    ///
+    /// Only enable this behind a proxy that appends to the header.
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_body_size: 64 * 1024,
+            submit_per_ip: None,
+            submit_per_token: None,
+            fetch_per_ip: None,
+            max_clients: 100_000,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+/// A token bucket rate limit.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateConfig {
+    /// The number of requests per second.
+    pub rate: f64,
+    /// The number of requests that can be made at once.
+    pub burst: u32,
+}
+
+/// Configures the removal of expired keys.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// The number of days before the current day that are kept.
+    pub days: u32,
+    /// How often expired days are removed, in seconds.
+    pub interval: u64,
+    /// If set, expired days are moved into this folder instead of deleted.
+    pub archive_path: Option<PathBuf>,
+    /// Compacts days that ended into compressed segments on every run.
+    pub compact: bool,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig {
+            days: DAYS_WINDOW,
+            interval: 3600,
+            archive_path: None,
+            compact: true,
+        }
+    }
+}
+
+/// Configures primary/replica replication.
+///
+/// Every instance streams its keys to replicas.  An instance with a
+/// `primary` is a replica: it copies all keys of the primary into its own
+/// store and rejects submissions.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// The base URL of the primary to replicate from.
+    pub primary: Option<String>,
+    /// A shared secret replicas have to send to stream keys.
+    ///
+    /// Without it or a client certificate authority in `tls` the stream
+    /// is closed.
+    pub token: Option<String>,
+    /// The maximum number of keys sent per batch.
+    pub batch_size: usize,
+    /// How often the primary checks for new keys, in milliseconds.
+    pub poll_interval: u64,
+    /// How often the primary confirms an idle stream, in seconds.
+    pub heartbeat_interval: u64,
+    /// How long a replica waits before reconnecting, in seconds.
+    pub retry_interval: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> ReplicationConfig {
+        ReplicationConfig {
+            primary: None,
+            token: None,
+            batch_size: 1000,
+            poll_interval: 250,
+            heartbeat_interval: 1,
+            retry_interval: 5,
+        }
+    }
+}
+
+/// Configures the Google/Apple compatible key export.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExportConfig {
+    /// Path to the PKCS#8 encoded P-256 signing key (PEM or DER).
+    pub signing_key: PathBuf,
+    /// The key id registered with the exposure notification framework.
+    pub key_id: String,
+    /// The version of the signing key.
+    #[serde(default = "default_key_version")]
+    pub key_version: String,
+    /// The region written into the export.
+    pub region: String,
+}
+
+fn default_key_version() -> String {
+    "v1".into()
+}
+
+/// Configures the exchange of keys with the backends of other regions.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FederationConfig {
+    /// The region keys submitted to this backend originate from.
+    pub region: String,
+    /// Path to the PKCS#8 encoded P-256 key batches are signed with (PEM or DER).
+    pub signing_key: PathBuf,
+    /// The backends keys are exchanged with.
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+    /// How often keys are exchanged with all peers, in seconds.
+    #[serde(default = "default_federation_interval")]
+    pub interval: u64,
+    /// The maximum number of keys per batch.
+    #[serde(default = "default_federation_batch_size")]
+    pub batch_size: usize,
+    /// The maximum size of batches uploaded by peers in bytes.
+    #[serde(default = "default_federation_max_body_size")]
+    pub max_body_size: u64,
+    /// A shared secret peers have to send to exchange batches.
+    ///
+    /// Without it or a client certificate authority in `tls` the
+    /// federation endpoints are closed.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// A backend of another region.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PeerConfig {
+    /// The region of the peer.
+    pub region: String,
+    /// The base URL of the peer.
+    pub url: String,
+    /// The base64 encoded public key of the peer as printed by the
+    /// `public-key` command.
+    pub public_key: String,
+    /// The federation token of the peer.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+fn default_federation_interval() -> u64 {
+    60
+}
+
+fn default_federation_batch_size() -> usize {
+    1000
+}
+
+fn default_federation_max_body_size() -> u64 {
+    4 * 1024 * 1024
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: ([127, 0, 0, 1], 5000).into(),
+            db_path: PathBuf::from("db"),
+            backend: StoreBackend::default(),
+            fsync: true,
+            read_only: false,
+            fetch_page_size: 1000,
+            cache: CacheConfig::default(),
+            retention: RetentionConfig::default(),
+            export: None,
+            replication: ReplicationConfig::default(),
+            federation: None,
+            limits: LimitsConfig::default(),
+            tls: None,
+            admin_token: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from a YAML file.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Config, io::Error> {
+        let contents = fs::read(p)?;
+        serde_yaml::from_slice(&contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Opens the configured storage backend.
+    pub fn open_store(&self) -> Result<Box<dyn KeyStore>, io::Error> {
+        Ok(match self.backend {
+            StoreBackend::File => {
+                let options = FileStoreOptions {
+                    fsync: self.fsync,
+                    read_only: self.read_only,
+                    retention_days: self.retention.days,
+                };
+                Box::new(FileKeyStore::open_with_options(&self.db_path, options)?)
+            }
+            StoreBackend::Memory if self.read_only => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the memory backend cannot be opened read-only",
+                ))
+            }
+            StoreBackend::Memory => {
+                Box::new(MemoryKeyStore::new().with_retention_days(self.retention.days))
+            }
+            StoreBackend::Sqlite if self.read_only => Box::new(
+                SqliteKeyStore::open_read_only(self.db_path.join("keys.sqlite"))?
+                    .with_retention_days(self.retention.days),
+            ),
+            StoreBackend::Sqlite => {
+                fs::create_dir_all(&self.db_path)?;
+                Box::new(
+                    SqliteKeyStore::open(self.db_path.join("keys.sqlite"))?
+                        .with_retention_days(self.retention.days),
+                )
+            }
+        })
+    }
+
+    /// Returns the folder with the federation state.
+    pub fn federation_path(&self) -> PathBuf {
+        self.db_path.join("federation")
+    }
+
+    /// Returns the folder of the store of a region.
+    pub fn partition_path(&self, region: &Region) -> PathBuf {
+        self.db_path.join("regions").join(region.as_str())
+    }
+
+    /// Opens the stores of all regions that already have keys.
+    ///
+    /// Stores of new regions are opened with the same backend on first use.
+    pub fn open_partitions(&self) -> Result<Partitions, io::Error> {
+        let config = self.clone();
+        let partitions = Partitions::new(move |region| {
+            Config {
+                db_path: config.partition_path(region),
+                ..config.clone()
+            }
+            .open_store()
+        });
+        let entries = match fs::read_dir(self.db_path.join("regions")) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(partitions),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            if let Some(region) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            {
+                partitions.open(&region)?;
+            }
+        }
+        Ok(partitions)
+    }
+
+    /// Loads the config from the path in `BACKEND_CONFIG`.
+    ///
+    /// If the environment variable is not set the defaults are used.
+    pub fn from_env() -> Result<Config, io::Error> {
+        match env::var_os("BACKEND_CONFIG") {
+            Some(path) => Config::from_path(path),
+            None => Ok(Config::default()),
+        }
+    }
+}
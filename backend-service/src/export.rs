@@ -0,0 +1,239 @@
+//! Support for the Google/Apple exposure notification key export format.
+//!
+//! An export is a zip file with two members: `export.bin` which holds a
+//! fixed header followed by a `TemporaryExposureKeyExport` protobuf message
+//! and `export.sig` which holds a `TEKSignatureList` with an ECDSA P-256
+//! signature over the entire `export.bin`.
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use prost::Message;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
+    ECDSA_P256_SHA256_ASN1_SIGNING,
+};
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use contact_tracing::DailyTracingKey;
+
+use crate::config::ExportConfig;
+use crate::proto::{
+    SignatureInfo, TekSignature, TekSignatureList, TemporaryExposureKey,
+    TemporaryExposureKeyExport, ECDSA_P256_SHA256_OID,
+};
+
+/// The header every `export.bin` starts with.
+pub const EXPORT_HEADER: &[u8; 16] = b"EK Export v1    ";
+
+/// The number of 10 minute intervals in a day.
+const INTERVALS_PER_DAY: u32 = 144;
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Signs key exports and federation batches.
+pub struct ExportSigner {
+    key_pair: EcdsaKeyPair,
+    key_id: String,
+    key_version: String,
+}
+
+impl fmt::Debug for ExportSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExportSigner")
+            .field("key_id", &self.key_id)
+            .field("key_version", &self.key_version)
+            .finish()
+    }
+}
+
+impl ExportSigner {
+    /// Creates a signer from a DER encoded PKCS#8 P-256 private key.
+    pub fn from_pkcs8(
+        pkcs8: &[u8],
+        key_id: &str,
+        key_version: &str,
+    ) -> Result<ExportSigner, io::Error> {
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8)
+            .map_err(|_| invalid_data("invalid PKCS#8 P-256 signing key"))?;
+        Ok(ExportSigner {
+            key_pair,
+            key_id: key_id.to_string(),
+            key_version: key_version.to_string(),
+        })
+    }
+
+    /// Loads a signer from a PKCS#8 file in PEM or DER format.
+    ///
+    /// A key in the right format can be created with openssl:
+    ///
+    /// ```text
+    /// openssl ecparam -name prime256v1 -genkey -noout | openssl pkcs8 -topk8 -nocrypt
+    /// ```
+    pub fn from_path<P: AsRef<Path>>(
+        p: P,
+        key_id: &str,
+        key_version: &str,
+    ) -> Result<ExportSigner, io::Error> {
+        let contents = fs::read(p)?;
+        let der = if contents.starts_with(b"-----BEGIN") {
+            let pem = String::from_utf8(contents).map_err(invalid_data)?;
+            let b64: String = pem
+                .lines()
+                .filter(|line| !line.starts_with("-----"))
+                .collect();
+            base64::decode(b64.trim()).map_err(invalid_data)?
+        } else {
+            contents
+        };
+        ExportSigner::from_pkcs8(&der, key_id, key_version)
+    }
+
+    /// Loads the signer from the export config.
+    pub fn from_config(config: &ExportConfig) -> Result<ExportSigner, io::Error> {
+        ExportSigner::from_path(&config.signing_key, &config.key_id, &config.key_version)
+    }
+
+    /// Returns the uncompressed public key of the signer.
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    fn signature_info(&self) -> SignatureInfo {
+        SignatureInfo {
+            verification_key_version: Some(self.key_version.clone()),
+            verification_key_id: Some(self.key_id.clone()),
+            signature_algorithm: Some(ECDSA_P256_SHA256_OID.into()),
+        }
+    }
+
+    /// Signs data with ECDSA P-256 and SHA-256.
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let sig = self
+            .key_pair
+            .sign(&SystemRandom::new(), data)
+            .map_err(|_| io::Error::other("could not sign export"))?;
+        Ok(sig.as_ref().to_vec())
+    }
+}
+
+/// The contents of a key export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportArchive {
+    pub region: String,
+    pub start_timestamp: DateTime<Utc>,
+    pub end_timestamp: DateTime<Utc>,
+    pub keys: Vec<(u32, DailyTracingKey)>,
+}
+
+impl ExportArchive {
+    /// Writes the export as signed zip archive.
+    pub fn to_zip(&self, signer: &ExportSigner) -> Result<Vec<u8>, io::Error> {
+        let export = TemporaryExposureKeyExport {
+            start_timestamp: Some(self.start_timestamp.timestamp() as u64),
+            end_timestamp: Some(self.end_timestamp.timestamp() as u64),
+            region: Some(self.region.clone()),
+            batch_num: Some(1),
+            batch_size: Some(1),
+            signature_infos: vec![signer.signature_info()],
+            keys: self
+                .keys
+                .iter()
+                .map(|(day, key)| TemporaryExposureKey {
+                    key_data: Some(key.as_bytes().to_vec()),
+                    rolling_start_interval_number: Some((day * INTERVALS_PER_DAY) as i32),
+                    rolling_period: Some(INTERVALS_PER_DAY as i32),
+                    ..Default::default()
+                })
+                .collect(),
+            revised_keys: vec![],
+        };
+
+        let mut bin = EXPORT_HEADER.to_vec();
+        export.encode(&mut bin).map_err(io::Error::other)?;
+
+        let signatures = TekSignatureList {
+            signatures: vec![TekSignature {
+                signature_info: Some(signer.signature_info()),
+                batch_num: Some(1),
+                batch_size: Some(1),
+                signature: Some(signer.sign(&bin)?),
+            }],
+        };
+        let mut sig = vec![];
+        signatures.encode(&mut sig).map_err(io::Error::other)?;
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("export.bin", FileOptions::default())?;
+        zip.write_all(&bin)?;
+        zip.start_file("export.sig", FileOptions::default())?;
+        zip.write_all(&sig)?;
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Parses a zip archive.
+    ///
+    /// If a public key is provided the signature is verified against it.
+    pub fn from_zip(data: &[u8], public_key: Option<&[u8]>) -> Result<ExportArchive, io::Error> {
+        let mut zip = ZipArchive::new(Cursor::new(data))?;
+        let mut bin = vec![];
+        zip.by_name("export.bin")?.read_to_end(&mut bin)?;
+        let mut sig = vec![];
+        zip.by_name("export.sig")?.read_to_end(&mut sig)?;
+
+        if !bin.starts_with(EXPORT_HEADER) {
+            return Err(invalid_data("invalid export header"));
+        }
+
+        if let Some(public_key) = public_key {
+            let signatures = TekSignatureList::decode(&sig[..]).map_err(invalid_data)?;
+            let key = UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key);
+            let verified = signatures.signatures.iter().any(|sig| {
+                sig.signature
+                    .as_ref()
+                    .is_some_and(|sig| key.verify(&bin, sig).is_ok())
+            });
+            if !verified {
+                return Err(invalid_data("bad export signature"));
+            }
+        }
+
+        let export = TemporaryExposureKeyExport::decode(&bin[EXPORT_HEADER.len()..])
+            .map_err(invalid_data)?;
+        let keys = export
+            .keys
+            .iter()
+            .map(|tek| {
+                let key = DailyTracingKey::from_bytes(tek.key_data.as_deref().unwrap_or_default())
+                    .map_err(invalid_data)?;
+                let day = tek.rolling_start_interval_number.unwrap_or(0) as u32 / INTERVALS_PER_DAY;
+                Ok((day, key))
+            })
+            .collect::<Result<_, io::Error>>()?;
+
+        Ok(ExportArchive {
+            region: export.region.unwrap_or_default(),
+            start_timestamp: timestamp_to_datetime(export.start_timestamp.unwrap_or(0))?,
+            end_timestamp: timestamp_to_datetime(export.end_timestamp.unwrap_or(0))?,
+            keys,
+        })
+    }
+}
+
+fn timestamp_to_datetime(ts: u64) -> Result<DateTime<Utc>, io::Error> {
+    i64::try_from(ts)
+        .ok()
+        .and_then(|ts| NaiveDateTime::from_timestamp_opt(ts, 0))
+        .map(|ts| Utc.from_utc_datetime(&ts))
+        .ok_or_else(|| invalid_data("invalid timestamp"))
+}
@@ -0,0 +1,657 @@
+//! Exchange of keys with the backends of other regions.
+//!
+//! Every backend serves the keys that were submitted to it as signed
+//! batches at `/federation/batches` and accepts the batches of its peers at
+//! `/federation/upload`.  A background task periodically uploads new local
+//! keys to every peer and downloads the batches of every peer, so keys
+//! still arrive if a peer was down while they were uploaded.  Keys that
+//! arrive twice are deduplicated by the store.
+//!
+//! Every key is tagged with the region it was submitted in and the
+//! countries the user visited.  Only keys of the own region are sent to
+//! peers so keys never travel in circles.  The tags of keys from other
+//! regions or with visited countries are kept in an append only file next
+//! to the store, keys without tags originate from the own region.
+//!
+//! A batch is a JSON encoded [`FederationBatch`] which is signed by the
+//! origin backend with ECDSA P-256 and sent as [`SignedBatch`].
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Method, Request};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use contact_tracing::DailyTracingKey;
+
+use crate::config::{FederationConfig, PeerConfig};
+use crate::export::ExportSigner;
+use crate::store::{AsyncKeyStore, KeyStore, Partitions, Region};
+use crate::tls::HttpClient;
+
+fn invalid_input<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// Checks that a region is an upper case two letter country code.
+pub fn is_valid_region(region: &str) -> bool {
+    region.parse::<Region>().is_ok()
+}
+
+pub(crate) fn validate_regions(regions: &[String]) -> Result<(), io::Error> {
+    match regions.iter().find(|region| !is_valid_region(region)) {
+        Some(region) => Err(invalid_input(format!("invalid country code {:?}", region))),
+        None => Ok(()),
+    }
+}
+
+/// The origin and visited countries of a key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyTags {
+    /// The region the key was submitted in.
+    pub origin: String,
+    /// The countries the user visited.
+    pub visited: Vec<String>,
+}
+
+/// A key in a federation batch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FederatedKey {
+    pub day: u32,
+    pub key: DailyTracingKey,
+    pub visited: Vec<String>,
+}
+
+/// A batch of keys that originate from one region.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FederationBatch {
+    /// The region all keys in the batch were submitted in.
+    pub origin: String,
+    pub keys: Vec<FederatedKey>,
+    /// The cursor to pass to download the next batch.
+    pub next_cursor: u64,
+    /// Indicates that more keys are available after this batch.
+    pub has_more: bool,
+}
+
+/// A batch with the signature of its origin backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedBatch {
+    /// The base64 encoded JSON of the [`FederationBatch`].
+    pub batch: String,
+    /// The base64 encoded ASN.1 ECDSA P-256 signature over the batch.
+    pub signature: String,
+}
+
+/// The response to an upload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadResponse {
+    /// The number of keys that were new.
+    pub added: usize,
+}
+
+/// A key with its tags as kept in the tag file and in snapshots.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TagRecord {
+    pub day: u32,
+    pub key: DailyTracingKey,
+    #[serde(flatten)]
+    pub tags: KeyTags,
+}
+
+/// Returns the path of the tag file in a federation state folder.
+fn tags_path(state_path: &Path) -> PathBuf {
+    state_path.join("tags.jsonl")
+}
+
+/// Reads the tags of all keys from a federation state folder.
+pub fn load_tags(state_path: &Path) -> Result<Vec<TagRecord>, io::Error> {
+    Ok(TagStore::open(tags_path(state_path))?.records())
+}
+
+/// Replaces the tags in a federation state folder.
+pub fn restore_tags(state_path: &Path, records: &[TagRecord]) -> Result<(), io::Error> {
+    fs::create_dir_all(state_path)?;
+    write_atomic(&tags_path(state_path), &encode_tag_records(records)?)
+}
+
+fn encode_tag_records(records: &[TagRecord]) -> Result<Vec<u8>, io::Error> {
+    let mut buf = vec![];
+    for record in records {
+        serde_json::to_writer(&mut buf, record)?;
+        buf.push(b'\n');
+    }
+    Ok(buf)
+}
+
+/// Keeps the tags of keys in an append only file.
+#[derive(Debug)]
+struct TagStore {
+    path: PathBuf,
+    tags: Mutex<HashMap<DailyTracingKey, (u32, KeyTags)>>,
+}
+
+impl TagStore {
+    fn open(path: PathBuf) -> Result<TagStore, io::Error> {
+        let mut tags = HashMap::new();
+        match fs::File::open(&path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    // a crash can leave a partial line behind
+                    match serde_json::from_str::<TagRecord>(&line?) {
+                        Ok(record) => {
+                            tags.insert(record.key, (record.day, record.tags));
+                        }
+                        Err(err) => {
+                            log::error!("skipped bad record in {}: {}", path.display(), err)
+                        }
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(TagStore {
+            path,
+            tags: Mutex::new(tags),
+        })
+    }
+
+    fn records(&self) -> Vec<TagRecord> {
+        TagStore::records_of(&self.tags.lock().unwrap())
+    }
+
+    fn records_of(map: &HashMap<DailyTracingKey, (u32, KeyTags)>) -> Vec<TagRecord> {
+        let mut records: Vec<_> = map
+            .iter()
+            .map(|(key, (day, tags))| TagRecord {
+                day: *day,
+                key: *key,
+                tags: tags.clone(),
+            })
+            .collect();
+        records.sort_by_key(|record| (record.day, record.key.as_bytes().to_vec()));
+        records
+    }
+
+    fn get(&self, key: &DailyTracingKey) -> Option<KeyTags> {
+        self.tags
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|(_, tags)| tags.clone())
+    }
+
+    fn insert(&self, day: u32, key: DailyTracingKey, tags: KeyTags) -> Result<(), io::Error> {
+        let mut map = self.tags.lock().unwrap();
+        let mut line = serde_json::to_vec(&TagRecord {
+            day,
+            key,
+            tags: tags.clone(),
+        })?;
+        line.push(b'\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+        map.insert(key, (day, tags));
+        Ok(())
+    }
+
+    /// Forgets the tags of all keys before the given day.
+    fn prune(&self, before: u32) -> Result<(), io::Error> {
+        let mut map = self.tags.lock().unwrap();
+        let count = map.len();
+        map.retain(|_, (day, _)| *day >= before);
+        if map.len() == count {
+            return Ok(());
+        }
+        write_atomic(
+            &self.path,
+            &encode_tag_records(&TagStore::records_of(&map))?,
+        )
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), io::Error> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// How far keys were exchanged with a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct PeerCursors {
+    /// The local cursor up to which keys were uploaded.
+    upload: u64,
+    /// The cursor of the peer up to which batches were downloaded.
+    download: u64,
+}
+
+/// A peer with its decoded public key.
+#[derive(Debug)]
+struct Peer {
+    config: PeerConfig,
+    public_key: Vec<u8>,
+}
+
+/// The part of the federation setup that can be reloaded.
+#[derive(Debug)]
+struct Settings {
+    config: FederationConfig,
+    signer: ExportSigner,
+    peers: Vec<Peer>,
+    client: HttpClient,
+}
+
+impl Settings {
+    fn load(config: FederationConfig, client: HttpClient) -> Result<Settings, io::Error> {
+        let signer = ExportSigner::from_path(&config.signing_key, &config.region, "v1")?;
+        let peers = config
+            .peers
+            .iter()
+            .map(|peer| {
+                Ok(Peer {
+                    config: peer.clone(),
+                    public_key: base64::decode(&peer.public_key).map_err(invalid_input)?,
+                })
+            })
+            .collect::<Result<_, io::Error>>()?;
+        Ok(Settings {
+            config,
+            signer,
+            peers,
+            client,
+        })
+    }
+}
+
+/// Exchanges keys with the backends of other regions.
+#[derive(Debug)]
+pub struct Federation<S> {
+    region: String,
+    settings: RwLock<Arc<Settings>>,
+    store: AsyncKeyStore<S>,
+    partitions: Arc<Partitions>,
+    tags: Arc<TagStore>,
+    cursors_path: PathBuf,
+    cursors: Mutex<BTreeMap<String, PeerCursors>>,
+}
+
+impl<S: KeyStore> Federation<S> {
+    /// Sets up federation for a store.
+    ///
+    /// Tags and the progress with every peer are kept in `state_path`.
+    /// Keys of peers are also added to the partition of their region.
+    pub fn open(
+        config: FederationConfig,
+        client: HttpClient,
+        store: AsyncKeyStore<S>,
+        partitions: Arc<Partitions>,
+        state_path: &Path,
+    ) -> Result<Federation<S>, io::Error> {
+        if !is_valid_region(&config.region) {
+            return Err(invalid_input(
+                "the federation region must be a country code",
+            ));
+        }
+        let region = config.region.clone();
+        let settings = Settings::load(config, client)?;
+
+        fs::create_dir_all(state_path)?;
+        let cursors_path = state_path.join("cursors.json");
+        let cursors = match fs::read(&cursors_path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Federation {
+            tags: Arc::new(TagStore::open(tags_path(state_path))?),
+            region,
+            settings: RwLock::new(Arc::new(settings)),
+            store,
+            partitions,
+            cursors_path,
+            cursors: Mutex::new(cursors),
+        })
+    }
+
+    /// Returns the region of this backend.
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// Returns the tags of all keys.
+    pub fn tag_records(&self) -> Vec<TagRecord> {
+        self.tags.records()
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Replaces the signing key, the peers, the client and the exchange
+    /// settings.
+    ///
+    /// The region cannot be changed as all stored tags refer to it.
+    pub fn reload(&self, config: FederationConfig, client: HttpClient) -> Result<(), io::Error> {
+        if config.region != self.region {
+            return Err(invalid_input("the federation region cannot be changed"));
+        }
+        *self.settings.write().unwrap() = Arc::new(Settings::load(config, client)?);
+        Ok(())
+    }
+
+    /// Adds keys with their tags and returns how many of them were new.
+    ///
+    /// Tags are written before the key so that a key from another region
+    /// can never be mistaken for a local one.
+    async fn add_tagged_keys(
+        &self,
+        keys: Vec<(u32, DailyTracingKey, KeyTags)>,
+    ) -> Result<usize, io::Error> {
+        let tags = self.tags.clone();
+        let region = self.region.clone();
+        self.store
+            .run(move |store| {
+                let mut added = 0;
+                for (day, key, key_tags) in keys {
+                    if store.has_key(key)? {
+                        continue;
+                    }
+                    if key_tags.origin != region || !key_tags.visited.is_empty() {
+                        tags.insert(day, key, key_tags)?;
+                    }
+                    if store.add_key(day, key)? {
+                        added += 1;
+                    }
+                }
+                Ok(added)
+            })
+            .await
+    }
+
+    /// Adds keys submitted to this backend.
+    pub async fn add_local_keys(
+        &self,
+        keys: Vec<(u32, DailyTracingKey)>,
+        visited: Vec<String>,
+    ) -> Result<usize, io::Error> {
+        validate_regions(&visited)?;
+        let tags = KeyTags {
+            origin: self.region.clone(),
+            visited,
+        };
+        self.add_tagged_keys(
+            keys.into_iter()
+                .map(|(day, key)| (day, key, tags.clone()))
+                .collect(),
+        )
+        .await
+    }
+
+    /// Returns the next batch of local keys after a cursor.
+    pub async fn local_batch(&self, cursor: u64) -> Result<FederationBatch, io::Error> {
+        let tags = self.tags.clone();
+        let region = self.region.clone();
+        let batch_size = self.settings().config.batch_size.max(1);
+        self.store
+            .run(move |store| {
+                let mut batch = FederationBatch {
+                    origin: region,
+                    keys: vec![],
+                    next_cursor: cursor,
+                    has_more: true,
+                };
+                while batch.has_more && batch.keys.len() < batch_size {
+                    let page = store
+                        .fetch_entries_since(batch.next_cursor, batch_size - batch.keys.len())?;
+                    for (_, day, key) in page.entries {
+                        let visited = match tags.get(&key) {
+                            Some(key_tags) if key_tags.origin != batch.origin => continue,
+                            Some(key_tags) => key_tags.visited,
+                            None => vec![],
+                        };
+                        batch.keys.push(FederatedKey { day, key, visited });
+                    }
+                    batch.next_cursor = page.next_cursor;
+                    batch.has_more = page.has_more;
+                }
+                Ok(batch)
+            })
+            .await
+    }
+
+    /// Signs a batch.
+    pub fn sign_batch(&self, batch: &FederationBatch) -> Result<SignedBatch, io::Error> {
+        let data = serde_json::to_vec(batch)?;
+        Ok(SignedBatch {
+            signature: base64::encode(self.settings().signer.sign(&data)?),
+            batch: base64::encode(&data),
+        })
+    }
+
+    /// Verifies the signature of a batch against the peer it claims to be
+    /// from.
+    pub fn verify_batch(&self, signed: &SignedBatch) -> Result<FederationBatch, io::Error> {
+        let data = base64::decode(&signed.batch).map_err(invalid_input)?;
+        let signature = base64::decode(&signed.signature).map_err(invalid_input)?;
+        let batch: FederationBatch = serde_json::from_slice(&data).map_err(invalid_input)?;
+        let settings = self.settings();
+        let peer = settings
+            .peers
+            .iter()
+            .find(|peer| peer.config.region == batch.origin)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::PermissionDenied, "batch from unknown region")
+            })?;
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &peer.public_key)
+            .verify(&data, &signature)
+            .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "bad batch signature"))?;
+        Ok(batch)
+    }
+
+    /// Verifies a batch of a peer and adds its keys.
+    ///
+    /// Returns the number of keys that were new.
+    pub async fn receive_batch(&self, signed: &SignedBatch) -> Result<usize, io::Error> {
+        self.add_batch(self.verify_batch(signed)?).await
+    }
+
+    /// Adds the keys of a verified batch.
+    ///
+    /// Keys for days outside of the retention window are skipped, peers
+    /// can retain days for longer.
+    async fn add_batch(&self, mut batch: FederationBatch) -> Result<usize, io::Error> {
+        let region: Region = batch.origin.parse()?;
+        let retained = self.store.retained_days();
+        batch.keys.retain(|key| retained.contains(&key.day));
+        let partition_keys = batch.keys.iter().map(|key| (key.day, key.key)).collect();
+        let mut keys = vec![];
+        for key in batch.keys {
+            validate_regions(&key.visited)?;
+            let tags = KeyTags {
+                origin: batch.origin.clone(),
+                visited: key.visited,
+            };
+            keys.push((key.day, key.key, tags));
+        }
+        let added = self.add_tagged_keys(keys).await?;
+        self.partitions
+            .open(&region)?
+            .add_keys(partition_keys)
+            .await?;
+        Ok(added)
+    }
+
+    fn peer_cursors(&self, peer: &Peer) -> PeerCursors {
+        self.cursors
+            .lock()
+            .unwrap()
+            .get(&peer.config.region)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn save_peer_cursors(&self, peer: &Peer, cursors: PeerCursors) -> Result<(), io::Error> {
+        let mut map = self.cursors.lock().unwrap();
+        map.insert(peer.config.region.clone(), cursors);
+        write_atomic(&self.cursors_path, &serde_json::to_vec_pretty(&*map)?)
+    }
+
+    /// Uploads new local keys to a peer and downloads its new batches.
+    ///
+    /// Returns the number of keys uploaded and the number of new keys
+    /// downloaded.
+    async fn sync_peer(
+        &self,
+        client: &HttpClient,
+        peer: &Peer,
+    ) -> Result<(usize, usize), io::Error> {
+        let url = peer.config.url.trim_end_matches('/');
+        let token = peer.config.token.as_deref();
+        let mut cursors = self.peer_cursors(peer);
+
+        let mut uploaded = 0;
+        loop {
+            let batch = self.local_batch(cursors.upload).await?;
+            if !batch.keys.is_empty() {
+                let signed = self.sign_batch(&batch)?;
+                let _: UploadResponse = http_json(
+                    client,
+                    Method::POST,
+                    &format!("{}/federation/upload", url),
+                    token,
+                    Some(&signed),
+                )
+                .await?;
+                uploaded += batch.keys.len();
+            }
+            cursors.upload = batch.next_cursor;
+            self.save_peer_cursors(peer, cursors)?;
+            if !batch.has_more {
+                break;
+            }
+        }
+
+        let mut downloaded = 0;
+        loop {
+            let signed: SignedBatch = http_json(
+                client,
+                Method::GET,
+                &format!("{}/federation/batches?since={}", url, cursors.download),
+                token,
+                None::<&()>,
+            )
+            .await?;
+            let batch = self.verify_batch(&signed)?;
+            if batch.origin != peer.config.region {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "peer sent a batch of another region",
+                ));
+            }
+            let (next_cursor, has_more) = (batch.next_cursor, batch.has_more);
+            downloaded += self.add_batch(batch).await?;
+            cursors.download = next_cursor;
+            self.save_peer_cursors(peer, cursors)?;
+            if !has_more {
+                break;
+            }
+        }
+
+        Ok((uploaded, downloaded))
+    }
+
+    /// Exchanges keys with all peers.
+    pub async fn sync(&self) {
+        let settings = self.settings();
+        for peer in &settings.peers {
+            match self.sync_peer(&settings.client, peer).await {
+                Ok((uploaded, downloaded)) => {
+                    if uploaded > 0 || downloaded > 0 {
+                        log::info!(
+                            "federation: uploaded {} and downloaded {} keys from {}",
+                            uploaded,
+                            downloaded,
+                            peer.config.region
+                        );
+                    }
+                }
+                Err(err) => log::error!(
+                    "federation: failed to exchange keys with {}: {}",
+                    peer.config.region,
+                    err
+                ),
+            }
+        }
+        let before = *self.store.retained_days().start();
+        if let Err(err) = self.tags.prune(before) {
+            log::error!("federation: failed to prune tags: {}", err);
+        }
+    }
+}
+
+/// Sends a JSON request and parses the JSON response.
+///
+/// The token is sent as bearer token if given.
+async fn http_json<T, R>(
+    client: &HttpClient,
+    method: Method,
+    url: &str,
+    token: Option<&str>,
+    body: Option<&T>,
+) -> Result<R, io::Error>
+where
+    T: Serialize,
+    R: DeserializeOwned,
+{
+    let body = match body {
+        Some(body) => Body::from(serde_json::to_vec(body)?),
+        None => Body::empty(),
+    };
+    let mut req = Request::builder()
+        .method(method)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(token) = token {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let req = req.body(body).map_err(invalid_input)?;
+    let res = client.request(req).await.map_err(io::Error::other)?;
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .map_err(io::Error::other)?;
+    if !status.is_success() {
+        return Err(io::Error::other(format!(
+            "{} responded with {}: {}",
+            url,
+            status,
+            String::from_utf8_lossy(&body)
+        )));
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Exchanges keys with all peers forever.
+///
+/// The first exchange happens immediately.
+pub async fn run_federation<S: KeyStore>(federation: Arc<Federation<S>>) {
+    loop {
+        federation.sync().await;
+        // the interval can change when the config is reloaded
+        let interval = federation.settings().config.interval.max(1);
+        tokio::time::delay_for(Duration::from_secs(interval)).await;
+    }
+}
@@ -0,0 +1,114 @@
+//! Liveness and readiness.
+//!
+//! `/healthz` only tells that the process serves requests.  `/readyz`
+//! additionally requires that the storage folder is writable, that the
+//! bucket of the current day can be loaded and that the store did not fail
+//! with an I/O error recently.  Days with corrupted data are counted but do
+//! not fail readiness.
+//!
+//! `/readyz` is public, so it only names the failed checks.  The errors
+//! behind them are logged.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::store::{AsyncKeyStore, KeyStore};
+
+/// How long an I/O error of the store keeps the service unready.
+const IO_ERROR_WINDOW: Duration = Duration::from_secs(30);
+
+/// Checks if an error means that the storage failed.
+///
+/// Errors caused by the request or by the store being read-only are not
+/// failures of the storage.
+pub fn is_storage_failure(err: &io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        io::ErrorKind::InvalidInput | io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// Tracks the I/O errors of a store.
+#[derive(Debug, Default)]
+pub struct Health {
+    last_io_error: Mutex<Option<(Instant, String)>>,
+}
+
+impl Health {
+    /// Records an I/O error of the store.
+    pub fn record_io_error(&self, err: &io::Error) {
+        *self.last_io_error.lock().unwrap() = Some((Instant::now(), err.to_string()));
+    }
+
+    /// Returns the last I/O error if it happened recently.
+    fn recent_io_error(&self) -> Option<String> {
+        match *self.last_io_error.lock().unwrap() {
+            Some((at, ref err)) if at.elapsed() < IO_ERROR_WINDOW => Some(err.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The result of a readiness check.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Readiness {
+    pub ready: bool,
+    /// The failed checks.
+    pub failures: Vec<&'static str>,
+    /// The number of days with corrupted data.
+    ///
+    /// Their intact keys are still served so they do not fail readiness.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub corrupt_days: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// Checks that a file can be created in a folder.
+fn check_writable(path: &Path) -> Result<(), io::Error> {
+    let probe = path.join(".readyz");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+/// Checks if the service is ready to serve requests.
+///
+/// The storage folder is only checked for writability if `writable_path`
+/// is given.
+pub async fn check_readiness<S: KeyStore>(
+    store: &AsyncKeyStore<S>,
+    writable_path: Option<&Path>,
+) -> Readiness {
+    let mut failures = vec![];
+    if let Some(path) = writable_path {
+        let path = path.to_owned();
+        if let Err(err) = tokio::task::spawn_blocking(move || check_writable(&path))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|rv| rv)
+        {
+            log::error!("not ready, storage is not writable: {}", err);
+            failures.push("storage is not writable");
+        }
+    }
+    let current_day = store.inner().current_day();
+    if let Err(err) = store.run(move |store| store.day_summary(current_day)).await {
+        log::error!("not ready, cannot load current day: {}", err);
+        failures.push("cannot load current day");
+    }
+    if let Some(err) = store.health().recent_io_error() {
+        log::error!("not ready, recent storage error: {}", err);
+        failures.push("recent storage error");
+    }
+    Readiness {
+        ready: failures.is_empty(),
+        failures,
+        corrupt_days: store.inner().corrupt_days().len(),
+    }
+}
@@ -0,0 +1,15 @@
+pub mod config;
+pub mod export;
+pub mod federation;
+pub mod health;
+pub mod metrics;
+pub mod proto;
+pub mod ratelimit;
+pub mod replication;
+pub mod retention;
+pub mod server;
+pub mod snapshot;
+pub mod store;
+pub mod submission;
+pub mod tls;
+pub mod utils;
@@ -1,9 +1,197 @@
-mod server;
-mod store;
-mod utils;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use http::header::AUTHORIZATION;
+use hyper::{Body, Request, Uri};
+use structopt::StructOpt;
+
+use backend_service::config::{Config, StoreBackend};
+use backend_service::export::ExportSigner;
+use backend_service::federation::load_tags;
+use backend_service::server;
+use backend_service::snapshot::Snapshot;
+use backend_service::tls::http_client;
+
+/// The contact tracing backend service.
+#[derive(StructOpt, Debug)]
+struct Cli {
+    /// Path to the config file.  Defaults to `BACKEND_CONFIG`.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Overrides the storage backend of the config (file, memory, sqlite).
+    #[structopt(long)]
+    backend: Option<StoreBackend>,
+    /// Overrides the store folder of the config.
+    #[structopt(long, parse(from_os_str))]
+    db_path: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Serves the API.  This is the default.
+    Serve,
+    /// Writes a snapshot of all keys in the store into a file.
+    ///
+    /// The store is locked while the snapshot is taken.  To take one while
+    /// the server is running, pass its URL with `--server`.
+    Snapshot {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Takes the snapshot through the running server at this URL.
+        #[structopt(long)]
+        server: Option<String>,
+    },
+    /// Restores a snapshot into an empty store.
+    ///
+    /// Combined with `--backend` this moves keys between storage backends.
+    Restore {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Verifies the checksum of a snapshot.
+    Verify {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Prints the base64 encoded public key of a signing key.
+    ///
+    /// Peers need this to verify federation batches.
+    PublicKey {
+        #[structopt(parse(from_os_str))]
+        signing_key: PathBuf,
+    },
+}
+
+impl Cli {
+    fn load_config(&self) -> Result<Config, io::Error> {
+        let mut config = match self.config {
+            Some(ref path) => Config::from_path(path)?,
+            None => Config::from_env()?,
+        };
+        if let Some(backend) = self.backend {
+            config.backend = backend;
+        }
+        if let Some(ref db_path) = self.db_path {
+            config.db_path = db_path.clone();
+        }
+        Ok(config)
+    }
+}
+
+fn read_snapshot(path: &Path) -> Result<Snapshot, io::Error> {
+    let snapshot = Snapshot::from_zip(&fs::read(path)?)?;
+    let manifest = snapshot.manifest();
+    println!(
+        "snapshot {} is valid ({} keys in {} days, checksum {})",
+        path.display(),
+        manifest.count,
+        manifest.days.len(),
+        manifest.checksum
+    );
+    Ok(snapshot)
+}
+
+/// Downloads a snapshot from a running server.
+async fn fetch_snapshot(config: &Config, url: &str) -> Result<Snapshot, io::Error> {
+    let uri: Uri = format!("{}/snapshot", url.trim_end_matches('/'))
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut req = Request::get(uri);
+    if let Some(ref token) = config.admin_token {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let req = req.body(Body::empty()).map_err(io::Error::other)?;
+    let res = http_client(config.tls.as_ref())?
+        .request(req)
+        .await
+        .map_err(io::Error::other)?;
+    if !res.status().is_success() {
+        return Err(io::Error::other(format!(
+            "server responded with {}",
+            res.status()
+        )));
+    }
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .map_err(io::Error::other)?;
+    Snapshot::from_zip(&body)
+}
+
+async fn run(mut cli: Cli) -> Result<(), io::Error> {
+    let config = cli.load_config()?;
+    match cli.command.take().unwrap_or(Command::Serve) {
+        Command::Serve => server::serve(config, move || cli.load_config()).await?,
+        Command::Snapshot { path, server } => {
+            let snapshot = match server {
+                Some(url) => fetch_snapshot(&config, &url).await?,
+                None => {
+                    let store = config.open_store().map_err(|err| {
+                        if err.kind() == io::ErrorKind::WouldBlock {
+                            io::Error::new(
+                                err.kind(),
+                                format!("{}, pass --server to snapshot a running server", err),
+                            )
+                        } else {
+                            err
+                        }
+                    })?;
+                    let mut snapshot = Snapshot::capture(&store)?;
+                    snapshot.capture_partitions(&config.open_partitions()?)?;
+                    if config.federation.is_some() {
+                        snapshot.tags = load_tags(&config.federation_path())?;
+                    }
+                    snapshot
+                }
+            };
+            let mut tmp_path = path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            fs::write(&tmp_path, snapshot.to_zip()?)?;
+            fs::rename(&tmp_path, &path)?;
+            let manifest = snapshot.manifest();
+            println!(
+                "wrote snapshot {} ({} keys in {} days, {} regions, checksum {})",
+                path.display(),
+                manifest.count,
+                manifest.days.len(),
+                manifest.partitions.len(),
+                manifest.checksum
+            );
+        }
+        Command::Restore { path } => {
+            let snapshot = read_snapshot(&path)?;
+            let store = config.open_store()?;
+            let restored = if config.federation.is_some() {
+                snapshot.restore_federated(&store, &config.federation_path())?
+            } else {
+                snapshot.restore(&store)?
+            };
+            let partition_keys = snapshot.restore_partitions(&config.open_partitions()?)?;
+            println!(
+                "restored {} keys and {} region keys into {}",
+                restored,
+                partition_keys,
+                config.db_path.display()
+            );
+        }
+        Command::Verify { path } => {
+            read_snapshot(&path)?;
+        }
+        Command::PublicKey { signing_key } => {
+            let signer = ExportSigner::from_path(signing_key, "", "")?;
+            println!("{}", base64::encode(signer.public_key()));
+        }
+    }
+    Ok(())
+}
 
 #[tokio::main]
 pub async fn main() {
     pretty_env_logger::init();
-    server::serve().await;
+    if let Err(err) = run(Cli::from_args()).await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
 }
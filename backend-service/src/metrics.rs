@@ -0,0 +1,243 @@
+//! Prometheus metrics.
+//!
+//! Metrics are collected in the global [`METRICS`] registry and rendered in
+//! the Prometheus text format at `/metrics`.  Gauges that describe the store
+//! (keys per day and size on disk) are read from the store on every scrape
+//! instead of being tracked.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::store::DayInfo;
+
+/// The upper bounds of the latency histogram buckets in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    /// The metrics of the process.
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// A latency histogram.
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += count;
+            writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        )
+        .unwrap();
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        writeln!(out, "{}_sum{} {}", name, labels, self.sum).unwrap();
+        writeln!(out, "{}_count{} {}", name, labels, self.count).unwrap();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    submissions_accepted: u64,
+    keys_accepted: u64,
+    submissions_rejected: BTreeMap<&'static str, u64>,
+    fetch_requests: BTreeMap<(&'static str, u16), u64>,
+    fetch_duration: BTreeMap<&'static str, Histogram>,
+    bucket_loads: Histogram,
+}
+
+/// Collects the metrics of the service.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+impl Metrics {
+    /// Records an accepted submission with the number of new keys.
+    ///
+    /// Fake submissions are recorded like real ones without new keys so
+    /// that the metrics cannot tell them apart.
+    pub fn submission_accepted(&self, new_keys: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.submissions_accepted += 1;
+        inner.keys_accepted += new_keys as u64;
+    }
+
+    /// Records a rejected submission.
+    pub fn submission_rejected(&self, reason: &'static str) {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .submissions_rejected
+            .entry(reason)
+            .or_insert(0) += 1;
+    }
+
+    /// Records a fetch request with its response status and duration.
+    pub fn fetch_request(&self, format: &'static str, status: u16, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.fetch_requests.entry((format, status)).or_insert(0) += 1;
+        inner
+            .fetch_duration
+            .entry(format)
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Records how long loading a bucket from disk took.
+    pub fn bucket_loaded(&self, duration: Duration) {
+        self.inner.lock().unwrap().bucket_loads.observe(duration);
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    ///
+    /// `days` and `disk_usage` describe the current state of the store.
+    pub fn render(&self, days: &[DayInfo], disk_usage: u64) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "backend_submissions_accepted_total",
+            "counter",
+            "Submissions that were accepted, including fake ones.",
+        );
+        writeln!(
+            out,
+            "backend_submissions_accepted_total {}",
+            inner.submissions_accepted
+        )
+        .unwrap();
+
+        write_header(
+            &mut out,
+            "backend_keys_accepted_total",
+            "counter",
+            "New keys stored by submissions.",
+        );
+        writeln!(out, "backend_keys_accepted_total {}", inner.keys_accepted).unwrap();
+
+        write_header(
+            &mut out,
+            "backend_submissions_rejected_total",
+            "counter",
+            "Submissions that were rejected by reason.",
+        );
+        for (reason, count) in &inner.submissions_rejected {
+            writeln!(
+                out,
+                "backend_submissions_rejected_total{{reason=\"{}\"}} {}",
+                reason, count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "backend_fetch_requests_total",
+            "counter",
+            "Fetch requests by response format and status.",
+        );
+        for ((format, status), count) in &inner.fetch_requests {
+            writeln!(
+                out,
+                "backend_fetch_requests_total{{format=\"{}\",status=\"{}\"}} {}",
+                format, status, count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "backend_fetch_duration_seconds",
+            "histogram",
+            "Latency of fetch requests by response format.",
+        );
+        for (format, histogram) in &inner.fetch_duration {
+            histogram.render(
+                &mut out,
+                "backend_fetch_duration_seconds",
+                &format!("format=\"{}\"", format),
+            );
+        }
+
+        write_header(
+            &mut out,
+            "backend_bucket_load_duration_seconds",
+            "histogram",
+            "Time it took to load a bucket from disk.",
+        );
+        inner
+            .bucket_loads
+            .render(&mut out, "backend_bucket_load_duration_seconds", "");
+
+        write_header(
+            &mut out,
+            "backend_keys",
+            "gauge",
+            "Keys per retained day bucket.",
+        );
+        for info in days {
+            writeln!(out, "backend_keys{{day=\"{}\"}} {}", info.day, info.count).unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "backend_storage_bytes",
+            "gauge",
+            "Size of the storage folder on disk.",
+        );
+        writeln!(out, "backend_storage_bytes {}", disk_usage).unwrap();
+
+        out
+    }
+}
@@ -0,0 +1,201 @@
+//! Protobuf messages.
+//!
+//! This contains the messages of the exposure notification key export
+//! format (mirroring `export.proto` as published by Google and Apple) and
+//! the protobuf representation of the API responses.
+use contact_tracing::DailyTracingKey;
+use prost::Message;
+
+use crate::store::{DayInfo, DayKeys, KeyPage};
+use crate::utils::ApiResponse;
+
+/// The signature algorithm for ECDSA with P-256 and SHA-256.
+pub const ECDSA_P256_SHA256_OID: &str = "1.2.840.10045.4.3.2";
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TemporaryExposureKeyExport {
+    #[prost(fixed64, optional, tag = "1")]
+    pub start_timestamp: Option<u64>,
+    #[prost(fixed64, optional, tag = "2")]
+    pub end_timestamp: Option<u64>,
+    #[prost(string, optional, tag = "3")]
+    pub region: Option<String>,
+    #[prost(int32, optional, tag = "4")]
+    pub batch_num: Option<i32>,
+    #[prost(int32, optional, tag = "5")]
+    pub batch_size: Option<i32>,
+    #[prost(message, repeated, tag = "6")]
+    pub signature_infos: Vec<SignatureInfo>,
+    #[prost(message, repeated, tag = "7")]
+    pub keys: Vec<TemporaryExposureKey>,
+    #[prost(message, repeated, tag = "8")]
+    pub revised_keys: Vec<TemporaryExposureKey>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SignatureInfo {
+    #[prost(string, optional, tag = "3")]
+    pub verification_key_version: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub verification_key_id: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub signature_algorithm: Option<String>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TemporaryExposureKey {
+    #[prost(bytes, optional, tag = "1")]
+    pub key_data: Option<Vec<u8>>,
+    #[prost(int32, optional, tag = "2")]
+    pub transmission_risk_level: Option<i32>,
+    #[prost(int32, optional, tag = "3")]
+    pub rolling_start_interval_number: Option<i32>,
+    #[prost(int32, optional, tag = "4")]
+    pub rolling_period: Option<i32>,
+    #[prost(int32, optional, tag = "5")]
+    pub report_type: Option<i32>,
+    #[prost(sint32, optional, tag = "6")]
+    pub days_since_onset_of_symptoms: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TekSignatureList {
+    #[prost(message, repeated, tag = "1")]
+    pub signatures: Vec<TekSignature>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TekSignature {
+    #[prost(message, optional, tag = "1")]
+    pub signature_info: Option<SignatureInfo>,
+    #[prost(int32, optional, tag = "2")]
+    pub batch_num: Option<i32>,
+    #[prost(int32, optional, tag = "3")]
+    pub batch_size: Option<i32>,
+    #[prost(bytes, optional, tag = "4")]
+    pub signature: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DayKeyMessage {
+    #[prost(uint32, tag = "1")]
+    pub day: u32,
+    #[prost(bytes, tag = "2")]
+    pub key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct KeyListMessage {
+    #[prost(message, repeated, tag = "1")]
+    pub keys: Vec<DayKeyMessage>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct KeyPageMessage {
+    #[prost(message, repeated, tag = "1")]
+    pub keys: Vec<DayKeyMessage>,
+    #[prost(uint64, tag = "2")]
+    pub next_cursor: u64,
+    #[prost(bool, tag = "3")]
+    pub has_more: bool,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DayInfoMessage {
+    #[prost(uint32, tag = "1")]
+    pub day: u32,
+    #[prost(uint64, tag = "2")]
+    pub count: u64,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DayListMessage {
+    #[prost(message, repeated, tag = "1")]
+    pub days: Vec<DayInfoMessage>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DayKeysMessage {
+    #[prost(uint32, tag = "1")]
+    pub day: u32,
+    #[prost(bytes, repeated, tag = "2")]
+    pub keys: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SubmitResponseMessage {
+    #[prost(string, tag = "1")]
+    pub padding: String,
+}
+
+pub(crate) fn encode<M: Message>(msg: &M) -> Vec<u8> {
+    let mut rv = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut rv).unwrap();
+    rv
+}
+
+fn day_key_messages(keys: &[(u32, DailyTracingKey)]) -> Vec<DayKeyMessage> {
+    keys.iter()
+        .map(|(day, key)| DayKeyMessage {
+            day: *day,
+            key: key.as_bytes().to_vec(),
+        })
+        .collect()
+}
+
+impl ApiResponse for () {
+    fn to_protobuf(&self) -> Vec<u8> {
+        vec![]
+    }
+}
+
+impl<T: ApiResponse> ApiResponse for Option<T> {
+    fn to_protobuf(&self) -> Vec<u8> {
+        self.as_ref().map_or_else(Vec::new, |x| x.to_protobuf())
+    }
+}
+
+impl ApiResponse for Vec<(u32, DailyTracingKey)> {
+    fn to_protobuf(&self) -> Vec<u8> {
+        encode(&KeyListMessage {
+            keys: day_key_messages(self),
+        })
+    }
+}
+
+impl ApiResponse for KeyPage {
+    fn to_protobuf(&self) -> Vec<u8> {
+        encode(&KeyPageMessage {
+            keys: day_key_messages(&self.keys),
+            next_cursor: self.next_cursor,
+            has_more: self.has_more,
+        })
+    }
+}
+
+impl ApiResponse for Vec<DayInfo> {
+    fn to_protobuf(&self) -> Vec<u8> {
+        encode(&DayListMessage {
+            days: self
+                .iter()
+                .map(|info| DayInfoMessage {
+                    day: info.day,
+                    count: info.count as u64,
+                })
+                .collect(),
+        })
+    }
+}
+
+impl ApiResponse for DayKeys {
+    fn to_protobuf(&self) -> Vec<u8> {
+        encode(&DayKeysMessage {
+            day: self.day,
+            keys: self
+                .keys
+                .iter()
+                .map(|key| key.as_bytes().to_vec())
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,183 @@
+//! Token bucket rate limits.
+//!
+//! Every client gets a bucket of `burst` tokens which refills at `rate`
+//! tokens per second, and every request takes one token.  Buckets are kept
+//! in memory for at most `max_clients` clients per limit.  When that is
+//! exceeded, full buckets are dropped first as they are the same as a new
+//! bucket, then the buckets that were used least recently until 90% of
+//! `max_clients` are left.  Evicting in bulk keeps new clients from
+//! scanning the whole table on every request.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::config::{LimitsConfig, RateConfig};
+
+/// The slowest refill rate, avoids dividing by zero.
+const MIN_RATE: f64 = 1e-6;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Returns the number of tokens at the given time.
+    fn tokens_at(&self, rate: &RateConfig, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate.rate.max(MIN_RATE)).min(rate.burst as f64)
+    }
+
+    fn refill(&mut self, rate: &RateConfig, now: Instant) {
+        self.tokens = self.tokens_at(rate, now);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState<K> {
+    rate: Option<RateConfig>,
+    max_clients: usize,
+    buckets: HashMap<K, Bucket>,
+}
+
+/// Rate limits requests per client.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    state: Mutex<LimiterState<K>>,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    /// Creates a limiter, `None` disables it.
+    pub fn new(rate: Option<RateConfig>, max_clients: usize) -> RateLimiter<K> {
+        RateLimiter {
+            state: Mutex::new(LimiterState {
+                rate,
+                max_clients: max_clients.max(1),
+                buckets: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Changes the limit, buckets are kept.
+    pub fn configure(&self, rate: Option<RateConfig>, max_clients: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate;
+        state.max_clients = max_clients.max(1);
+        if rate.is_none() {
+            state.buckets.clear();
+        }
+    }
+
+    /// Returns the number of tracked clients.
+    pub fn clients(&self) -> usize {
+        self.state.lock().unwrap().buckets.len()
+    }
+
+    /// Takes a token for a client.
+    ///
+    /// If the client has no tokens left the time until the next token is
+    /// returned as error.
+    pub fn check(&self, client: &K) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    /// Takes a token for a client at the given time.
+    pub fn check_at(&self, client: &K, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let rate = match state.rate {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        if !state.buckets.contains_key(client) && state.buckets.len() >= state.max_clients {
+            let max_clients = state.max_clients;
+            evict(&mut state.buckets, &rate, now, max_clients);
+        }
+        let bucket = state.buckets.entry(client.clone()).or_insert(Bucket {
+            tokens: rate.burst as f64,
+            updated: now,
+        });
+        bucket.refill(&rate, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / rate.rate.max(MIN_RATE);
+            Err(Duration::from_secs_f64(wait.min(u32::MAX as f64)))
+        }
+    }
+}
+
+/// Makes room for new buckets.
+fn evict<K: Hash + Eq + Clone>(
+    buckets: &mut HashMap<K, Bucket>,
+    rate: &RateConfig,
+    now: Instant,
+    max_clients: usize,
+) {
+    buckets.retain(|_, bucket| bucket.tokens_at(rate, now) < rate.burst as f64);
+    let target = max_clients - (max_clients / 10).max(1);
+    if buckets.len() <= target {
+        return;
+    }
+    let excess = buckets.len() - target;
+    let mut by_age: Vec<_> = buckets
+        .iter()
+        .map(|(client, bucket)| (bucket.updated, client.clone()))
+        .collect();
+    by_age.select_nth_unstable_by_key(excess - 1, |(updated, _)| *updated);
+    for (_, client) in &by_age[..excess] {
+        buckets.remove(client);
+    }
+}
+
+/// The rate limits of the API.
+#[derive(Debug)]
+pub struct RateLimits {
+    submit_per_ip: RateLimiter<IpAddr>,
+    submit_per_token: RateLimiter<[u8; 32]>,
+    fetch_per_ip: RateLimiter<IpAddr>,
+}
+
+impl RateLimits {
+    /// Creates the limits from the config.
+    pub fn new(config: &LimitsConfig) -> RateLimits {
+        RateLimits {
+            submit_per_ip: RateLimiter::new(config.submit_per_ip, config.max_clients),
+            submit_per_token: RateLimiter::new(config.submit_per_token, config.max_clients),
+            fetch_per_ip: RateLimiter::new(config.fetch_per_ip, config.max_clients),
+        }
+    }
+
+    /// Applies a reloaded config.
+    pub fn configure(&self, config: &LimitsConfig) {
+        self.submit_per_ip
+            .configure(config.submit_per_ip, config.max_clients);
+        self.submit_per_token
+            .configure(config.submit_per_token, config.max_clients);
+        self.fetch_per_ip
+            .configure(config.fetch_per_ip, config.max_clients);
+    }
+
+    /// Checks the limits of a submission.
+    pub fn check_submit(&self, ip: IpAddr, token: Option<&str>) -> Result<(), Duration> {
+        self.submit_per_ip.check(&ip)?;
+        if let Some(token) = token {
+            // only a digest is kept so that long tokens cannot fill memory
+            let mut digest = [0; 32];
+            digest.copy_from_slice(&Sha256::digest(token.as_bytes()));
+            self.submit_per_token.check(&digest)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the limits of a fetch.
+    pub fn check_fetch(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.fetch_per_ip.check(&ip)
+    }
+}
@@ -0,0 +1,317 @@
+//! Primary/replica replication.
+//!
+//! A replica opens a long lived request to `/replication/stream` on the
+//! primary which streams newline delimited JSON events.  Keys are sent in
+//! batches, each followed by a heartbeat carrying the cursor after the
+//! batch.  When there are no new keys the primary still sends a heartbeat
+//! every `heartbeat_interval` so that replicas can tell they are caught up.
+//!
+//! Replicas import keys with the sequence numbers of the primary so that
+//! cursors can be used against any instance, and resume from the highest
+//! sequence number in their own store after a restart.
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, Stream};
+use http::header::AUTHORIZATION;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Uri};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::time::{delay_for, timeout};
+
+use contact_tracing::DailyTracingKey;
+
+use crate::config::ReplicationConfig;
+use crate::store::{AsyncKeyStore, EntryPage, KeyStore};
+use crate::tls::HttpClient;
+
+/// An event in the replication stream.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplicationEvent {
+    /// A key with the sequence number it has on the primary.
+    Key {
+        seq: u64,
+        day: u32,
+        key: DailyTracingKey,
+    },
+    /// Ends a batch.
+    Heartbeat {
+        /// The cursor to resume from after this batch.
+        cursor: u64,
+        /// Indicates that the primary has more keys after this batch.
+        has_more: bool,
+    },
+}
+
+/// Encodes a page of keys as a batch of events.
+fn encode_batch(page: EntryPage) -> Result<Bytes, io::Error> {
+    let mut buf = vec![];
+    let heartbeat = ReplicationEvent::Heartbeat {
+        cursor: page.next_cursor,
+        has_more: page.has_more,
+    };
+    let keys = page
+        .entries
+        .into_iter()
+        .map(|(seq, day, key)| ReplicationEvent::Key { seq, day, key });
+    for event in keys.chain(Some(heartbeat)) {
+        serde_json::to_writer(&mut buf, &event)?;
+        buf.push(b'\n');
+    }
+    Ok(buf.into())
+}
+
+/// Waits until `shutdown` becomes `true` or its sender is dropped.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    while let Some(false) = shutdown.recv().await {}
+}
+
+/// Sleeps unless the server shuts down first.
+///
+/// Returns `false` if the sleep was cut short.
+async fn sleep_unless_shutdown(duration: Duration, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = delay_for(duration) => true,
+        _ = shutdown_requested(shutdown) => false,
+    }
+}
+
+/// Streams all keys starting at a cursor and then tails new ones.
+///
+/// While tailing, the store is only scanned again once its
+/// [`seq_watermark`](KeyStore::seq_watermark) moved.  The stream ends with
+/// an error if the store fails and ends cleanly once `shutdown` becomes
+/// `true` so that graceful shutdowns do not wait for replicas.
+pub fn stream_keys<S: KeyStore>(
+    store: AsyncKeyStore<S>,
+    since: u64,
+    config: &ReplicationConfig,
+    shutdown: watch::Receiver<bool>,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let batch_size = config.batch_size.max(1);
+    let poll_interval = Duration::from_millis(config.poll_interval.max(1));
+    let heartbeat_interval = Duration::from_secs(config.heartbeat_interval.max(1));
+    stream::unfold(
+        Some((store, since, None, shutdown)),
+        move |state| async move {
+            let (store, cursor, mut scanned, mut shutdown) = state?;
+            let deadline = Instant::now() + heartbeat_interval;
+            loop {
+                if *shutdown.borrow() {
+                    return None;
+                }
+                let watermark = store.inner().seq_watermark();
+                if watermark.is_some() && watermark == scanned && Instant::now() < deadline {
+                    if !sleep_unless_shutdown(poll_interval, &mut shutdown).await {
+                        return None;
+                    }
+                    continue;
+                }
+                let page = match store.fetch_entries_since(cursor, batch_size).await {
+                    Ok(page) => page,
+                    Err(err) => return Some((Err(err), None)),
+                };
+                scanned = watermark;
+                if page.entries.is_empty() && Instant::now() < deadline {
+                    if !sleep_unless_shutdown(poll_interval, &mut shutdown).await {
+                        return None;
+                    }
+                    continue;
+                }
+                // a full page can be followed by more keys right away
+                let scanned = if page.entries.is_empty() {
+                    scanned
+                } else {
+                    None
+                };
+                let next_cursor = page.next_cursor;
+                let state = (store, next_cursor, scanned, shutdown);
+                return Some((encode_batch(page), Some(state)));
+            }
+        },
+    )
+}
+
+/// The replication status of an instance.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum ReplicationStatus {
+    Primary,
+    Replica(ReplicaStatus),
+}
+
+/// The progress of a replica.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ReplicaStatus {
+    /// The primary that is replicated from.
+    pub primary: String,
+    /// Indicates that the replica is currently streaming from the primary.
+    pub connected: bool,
+    /// The cursor on the primary that replication continues from.
+    pub cursor: u64,
+    /// The number of keys applied since the replica started.
+    pub applied: u64,
+    /// Seconds since the replica last had all keys of the primary.
+    pub lag_seconds: f64,
+}
+
+#[derive(Debug, Default)]
+struct ReplicaState {
+    connected: bool,
+    cursor: u64,
+    applied: u64,
+    caught_up_at: Option<Instant>,
+}
+
+/// Keeps track of the progress of a replica.
+#[derive(Debug)]
+pub struct Replica {
+    primary: String,
+    started: Instant,
+    state: Mutex<ReplicaState>,
+}
+
+impl Replica {
+    /// Creates the tracker for a replica of the given primary.
+    pub fn new(primary: &str) -> Replica {
+        Replica {
+            primary: primary.trim_end_matches('/').into(),
+            started: Instant::now(),
+            state: Mutex::new(ReplicaState::default()),
+        }
+    }
+
+    /// Returns the current status.
+    pub fn status(&self) -> ReplicaStatus {
+        let state = self.state.lock().unwrap();
+        ReplicaStatus {
+            primary: self.primary.clone(),
+            connected: state.connected,
+            cursor: state.cursor,
+            applied: state.applied,
+            lag_seconds: state
+                .caught_up_at
+                .unwrap_or(self.started)
+                .elapsed()
+                .as_secs_f64(),
+        }
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.state.lock().unwrap().connected = connected;
+    }
+
+    fn record_batch(&self, cursor: u64, applied: usize, caught_up: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.cursor = cursor;
+        state.applied += applied as u64;
+        if caught_up {
+            state.caught_up_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Streams keys from the primary until the connection fails.
+async fn replicate<S: KeyStore>(
+    client: &HttpClient,
+    store: &AsyncKeyStore<S>,
+    config: &ReplicationConfig,
+    replica: &Replica,
+    cursor: &mut u64,
+) -> Result<(), io::Error> {
+    let uri: Uri = format!("{}/replication/stream?since={}", replica.primary, cursor)
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut req = Request::get(uri);
+    if let Some(ref token) = config.token {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let req = req.body(Body::empty()).map_err(io::Error::other)?;
+    let res = client.request(req).await.map_err(io::Error::other)?;
+    if !res.status().is_success() {
+        return Err(io::Error::other(format!(
+            "primary responded with {}",
+            res.status()
+        )));
+    }
+
+    replica.set_connected(true);
+    log::info!(
+        "replication: streaming from {} at {}",
+        replica.primary,
+        cursor
+    );
+    // the primary sends heartbeats, a stream that stays silent for longer
+    // is considered dead
+    let idle_timeout = Duration::from_secs(config.heartbeat_interval.max(1) * 5);
+    let mut body = res.into_body();
+    let mut buf = BytesMut::new();
+    let mut batch = vec![];
+    loop {
+        let chunk = match timeout(idle_timeout, body.data()).await {
+            Ok(Some(chunk)) => chunk.map_err(io::Error::other)?,
+            Ok(None) => return Err(io::Error::other("primary closed the stream")),
+            Err(_) => return Err(io::Error::other("primary stopped sending heartbeats")),
+        };
+        buf.extend_from_slice(&chunk);
+        while let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let line = buf.split_to(pos + 1);
+            match serde_json::from_slice(&line[..pos])? {
+                ReplicationEvent::Key { seq, day, key } => batch.push((seq, day, key)),
+                ReplicationEvent::Heartbeat {
+                    cursor: next_cursor,
+                    has_more,
+                } => {
+                    let entries = std::mem::take(&mut batch);
+                    let applied = store
+                        .run(move |store| {
+                            let mut applied = 0;
+                            for (seq, day, key) in entries {
+                                if store.import_key(seq, day, key)? {
+                                    applied += 1;
+                                }
+                            }
+                            Ok(applied)
+                        })
+                        .await?;
+                    *cursor = next_cursor;
+                    replica.record_batch(next_cursor, applied, !has_more);
+                }
+            }
+        }
+    }
+}
+
+/// Replicates from the primary forever, reconnecting on errors.
+pub async fn run_replica<S: KeyStore>(
+    store: AsyncKeyStore<S>,
+    config: ReplicationConfig,
+    replica: Arc<Replica>,
+    client: HttpClient,
+) {
+    // keys keep the sequence numbers of the primary, so the replica can
+    // resume after the highest one it has
+    let mut cursor = loop {
+        match store
+            .run(|store| Ok(store.dump()?.last().map_or(0, |&(seq, _, _)| seq + 1)))
+            .await
+        {
+            Ok(cursor) => break cursor,
+            Err(err) => {
+                log::error!("replication: failed to read the store: {}", err);
+                delay_for(Duration::from_secs(config.retry_interval)).await;
+            }
+        }
+    };
+    loop {
+        if let Err(err) = replicate(&client, &store, &config, &replica, &mut cursor).await {
+            log::error!("replication: {}", err);
+        }
+        replica.set_connected(false);
+        delay_for(Duration::from_secs(config.retry_interval)).await;
+    }
+}
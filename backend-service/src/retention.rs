@@ -0,0 +1,73 @@
+//! Removal of expired keys.
+//!
+//! Keys must not be kept for longer than the retention window, so a
+//! background task periodically deletes (or archives) all days before it
+//! and logs every day it removed.  The same task compacts days that ended.
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::RetentionConfig;
+use crate::store::{AsyncKeyStore, DayInfo, KeyStore, Partitions};
+
+/// Removes or archives all days that are outside the retention window.
+///
+/// Returns the days that were removed.
+pub fn enforce_retention<S: KeyStore>(
+    store: &S,
+    config: &RetentionConfig,
+) -> Result<Vec<DayInfo>, io::Error> {
+    let before = store.current_day().saturating_sub(config.days);
+    let removed = match config.archive_path {
+        Some(ref archive_path) => store.archive(before, archive_path)?,
+        None => store.purge(before)?,
+    };
+    for info in &removed {
+        match config.archive_path {
+            Some(ref archive_path) => log::info!(
+                "retention: archived day {} ({} keys) to {}",
+                info.day,
+                info.count,
+                archive_path.display()
+            ),
+            None => log::info!("retention: deleted day {} ({} keys)", info.day, info.count),
+        }
+    }
+    Ok(removed)
+}
+
+/// Removes expired days from a store and compacts it.
+async fn maintain<S: KeyStore>(store: &AsyncKeyStore<S>, config: &RetentionConfig) {
+    let retention_config = config.clone();
+    if let Err(err) = store
+        .run(move |store| enforce_retention(store, &retention_config))
+        .await
+    {
+        log::error!("retention: failed to remove expired days: {}", err);
+    }
+    if config.compact {
+        if let Err(err) = store.run(|store| store.compact()).await {
+            log::error!("retention: failed to compact closed days: {}", err);
+        }
+    }
+}
+
+/// Runs the retention and compaction task forever.
+///
+/// The task takes care of the store and the partitions of all regions.
+/// The first run happens immediately.
+pub async fn run_retention<S: KeyStore>(
+    store: AsyncKeyStore<S>,
+    partitions: Arc<Partitions>,
+    config: RetentionConfig,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        interval.tick().await;
+        maintain(&store, &config).await;
+        for (region, partition) in partitions.all() {
+            log::debug!("retention: maintaining region {}", region);
+            maintain(&partition, &config).await;
+        }
+    }
+}
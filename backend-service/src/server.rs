@@ -1,30 +1,499 @@
-use std::convert::Infallible;
-use std::sync::Arc;
+use std::convert::{Infallible, TryFrom};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, TcpListener as StdTcpListener};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use chrono::{NaiveDateTime, TimeZone, Utc};
-use contact_tracing::DailyTracingKey;
-use hyper::{service::make_service_fn, Server};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use futures::future::{abortable, AbortHandle};
+use http::header::CONTENT_TYPE;
+use http::StatusCode;
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
+use hyper::{service::make_service_fn, Body, Response, Server};
+use ring::constant_time::verify_slices_are_equal;
 use serde::{Deserialize, Serialize};
-use warp::Filter;
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task;
+use tokio_rustls::server::TlsStream;
+use warp::{Filter, Rejection, Reply};
+
+use contact_tracing::DailyTracingKey;
+
+use crate::config::{Config, LimitsConfig, StoreBackend};
+use crate::export::{ExportArchive, ExportSigner};
+use crate::federation::{
+    run_federation, validate_regions, Federation, SignedBatch, UploadResponse,
+};
+use crate::health::check_readiness;
+use crate::metrics::METRICS;
+use crate::ratelimit::RateLimits;
+use crate::replication::{run_replica, stream_keys, Replica, ReplicationStatus};
+use crate::retention::run_retention;
+use crate::snapshot::Snapshot;
+use crate::store::{disk_usage, AsyncKeyStore, CacheInfo, KeyStore, Partitions, Region};
+use crate::submission::{SubmissionTimer, SubmitResponse};
+use crate::tls::{http_client, is_client_authenticated, TlsServer};
+use crate::utils::{
+    api_reply, cache_conditions, cached_api_reply, decode_body, handle_rejection, limited_body,
+    reject_io, response_format, ApiError, CacheConditions, ResponseFormat,
+};
+
+#[derive(Debug)]
+pub struct BackendState<S> {
+    config: RwLock<Arc<Config>>,
+    store: AsyncKeyStore<S>,
+    partitions: Arc<Partitions>,
+    export_signer: RwLock<Option<Arc<ExportSigner>>>,
+    replica: Option<Arc<Replica>>,
+    federation: Option<Arc<Federation<S>>>,
+    limits: RateLimits,
+    submission_timer: SubmissionTimer,
+    tls: Option<Arc<TlsServer>>,
+    /// Becomes `true` once the server shuts down.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl<S: KeyStore> BackendState<S> {
+    /// Returns the current config.
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Returns the partition of a region for reading.
+    ///
+    /// Replicas only replicate the global store, so they cannot serve
+    /// regions.
+    fn partition(&self, region: &Region) -> Result<AsyncKeyStore<Box<dyn KeyStore>>, Rejection> {
+        if self.replica.is_some() {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "regions are not replicated, fetch them from the primary",
+            )
+            .reject());
+        }
+        Ok(self.partitions.get(region))
+    }
+
+    /// Returns the current export signer.
+    fn export_signer(&self) -> Option<Arc<ExportSigner>> {
+        self.export_signer.read().unwrap().clone()
+    }
+
+    /// Returns the max age for a cached response.
+    fn max_age(&self, info: &CacheInfo) -> u64 {
+        let config = self.config();
+        if info.closed {
+            config.cache.closed_max_age
+        } else {
+            config.cache.open_max_age
+        }
+    }
+
+    /// Switches to a reloaded config and reloads the signing keys and the
+    /// TLS certificate.
+    ///
+    /// The listen address, the storage, retention and the replication
+    /// primary are only read at startup and keep their running values, as
+    /// does whether federation and TLS are enabled.  Nothing changes if the
+    /// new config, a signing key or the certificate is invalid.
+    fn reload(&self, mut config: Config) -> Result<(), io::Error> {
+        let current = self.config();
+        config.listen = current.listen;
+        config.db_path = current.db_path.clone();
+        config.backend = current.backend;
+        config.fsync = current.fsync;
+        config.read_only = current.read_only;
+        config.retention = current.retention.clone();
+        config.replication.primary = current.replication.primary.clone();
 
-use crate::store::DailyTracingKeyStore;
-use crate::utils::{api_reply, response_format};
+        let export_signer = config
+            .export
+            .as_ref()
+            .map(|export| ExportSigner::from_config(export).map(Arc::new))
+            .transpose()?;
+        let tls_settings = match (&self.tls, &config.tls) {
+            (Some(_), Some(tls_config)) => Some(TlsServer::load(tls_config)?),
+            (None, None) => None,
+            _ => {
+                log::warn!("enabling or disabling TLS requires a restart");
+                config.tls = current.tls.clone();
+                None
+            }
+        };
+        match (&self.federation, &config.federation) {
+            (Some(federation), Some(federation_config)) => {
+                federation.reload(federation_config.clone(), http_client(config.tls.as_ref())?)?
+            }
+            (None, None) => {}
+            _ => {
+                log::warn!("enabling or disabling federation requires a restart");
+                config.federation = current.federation.clone();
+            }
+        }
+
+        check_auth(&config)?;
+
+        if let (Some(tls), Some(tls_settings)) = (&self.tls, tls_settings) {
+            tls.replace(tls_settings);
+        }
+        self.limits.configure(&config.limits);
+        *self.export_signer.write().unwrap() = export_signer;
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+}
+
+pub use crate::submission::DailyTracingKeyStoreRequest;
 
-#[derive(Debug, Clone)]
-pub struct BackendState {
-    store: Arc<DailyTracingKeyStore>,
+#[derive(Serialize, Debug)]
+struct HealthStatus {
+    status: &'static str,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DailyTracingKeyStoreRequest {
-    keys: Vec<(u32, DailyTracingKey)>,
+#[derive(Deserialize, Debug)]
+pub struct FetchSinceQuery {
+    since: u64,
 }
 
-pub async fn serve() {
-    let backend_state = Arc::new(BackendState {
-        store: Arc::new(DailyTracingKeyStore::open("db").unwrap()),
-    });
+/// Checks the bearer token replicas send against the configured one.
+/// The endpoints that are not public.
+#[derive(Debug, Clone, Copy)]
+enum Access {
+    Admin,
+    Replication,
+    Federation,
+}
+
+impl Access {
+    /// Returns the token that grants access.
+    fn token(self, config: &Config) -> Option<&str> {
+        match self {
+            Access::Admin => config.admin_token.as_deref(),
+            Access::Replication => config.replication.token.as_deref(),
+            Access::Federation => config
+                .federation
+                .as_ref()
+                .and_then(|federation| federation.token.as_deref()),
+        }
+    }
+}
+
+/// Checks that the non-public endpoints can be reached by someone.
+///
+/// Peers could never exchange keys with a closed federation endpoint, so
+/// federation without a token or a client certificate authority is an
+/// error.  Closed admin and replication endpoints are only logged.
+fn check_auth(config: &Config) -> Result<(), io::Error> {
+    let client_ca = config
+        .tls
+        .as_ref()
+        .is_some_and(|tls| tls.client_ca_path.is_some());
+    if client_ca {
+        return Ok(());
+    }
+    let is_replica = config.replication.primary.is_some();
+    if !is_replica && Access::Federation.token(config).is_none() && config.federation.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "federation requires federation.token or tls.client_ca_path",
+        ));
+    }
+    if Access::Admin.token(config).is_none() {
+        log::warn!(
+            "the admin endpoints are closed, set admin_token or tls.client_ca_path to use them"
+        );
+    }
+    if !is_replica && Access::Replication.token(config).is_none() {
+        log::warn!(
+            "the replication stream is closed, set replication.token or tls.client_ca_path to use it"
+        );
+    }
+    Ok(())
+}
+
+/// Checks the access to a non-public endpoint.
+///
+/// A client certificate of a configured authority or the token of the
+/// endpoint grant access.  Endpoints without either are closed.
+fn check_access(
+    connection: Connection,
+    token: Option<&str>,
+    authorization: Option<String>,
+) -> Result<(), Rejection> {
+    if connection.client_authenticated {
+        return Ok(());
+    }
+    let expected = match token {
+        Some(token) => format!("Bearer {}", token),
+        None => {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "client certificate or token required",
+            )
+            .reject())
+        }
+    };
+    let given = authorization.unwrap_or_default();
+    verify_slices_are_equal(given.as_bytes(), expected.as_bytes())
+        .map_err(|_| ApiError::new(StatusCode::UNAUTHORIZED, "bad token").reject())
+}
+
+/// Information about the connection a request came in on.
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    remote: IpAddr,
+    /// The client presented a certificate of a configured authority.
+    client_authenticated: bool,
+}
+
+/// Returns the IP address of the client.
+///
+/// Behind a trusted proxy this is the last address of `X-Forwarded-For`,
+/// the one the proxy appended.  Earlier entries come from the client and
+/// can be forged.
+fn client_ip(remote: IpAddr, forwarded_for: Option<String>, config: &LimitsConfig) -> IpAddr {
+    if !config.trust_forwarded_for {
+        return remote;
+    }
+    forwarded_for
+        .as_deref()
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|addr| addr.trim().parse().ok())
+        .unwrap_or(remote)
+}
+
+/// Rejects a request that exceeded a rate limit.
+fn rate_limited(retry_after: Duration) -> Rejection {
+    ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded")
+        .with_retry_after(retry_after)
+        .reject()
+}
+
+/// Rejects submissions with keys for days outside of the retention window.
+///
+/// This runs before anything is stored so that a submission is never
+/// stored in parts.
+fn check_submission_days<S: KeyStore>(
+    state: &BackendState<S>,
+    keys: &[(u32, DailyTracingKey)],
+) -> Result<(), io::Error> {
+    keys.iter()
+        .try_for_each(|&(day, _)| state.store.inner().check_day(day))
+}
+
+/// Stores the keys of a submission and returns how many of them were new.
+async fn store_submission<S: KeyStore>(
+    state: &BackendState<S>,
+    data: DailyTracingKeyStoreRequest,
+) -> Result<usize, io::Error> {
+    let DailyTracingKeyStoreRequest {
+        keys,
+        visited_countries,
+        region,
+        ..
+    } = data;
+    check_submission_days(state, &keys)?;
+    let region_keys = region.map(|region| (region, keys.clone()));
+    let added = match state.federation {
+        Some(ref federation) => federation.add_local_keys(keys, visited_countries).await?,
+        None => state.store.add_keys(keys).await?,
+    };
+    if let Some((region, keys)) = region_keys {
+        let partitions = state.partitions.clone();
+        task::spawn_blocking(move || partitions.open(&region))
+            .await
+            .map_err(io::Error::other)??
+            .add_keys(keys)
+            .await?;
+    }
+    Ok(added)
+}
+
+/// Validates a fake submission like a real one without storing it.
+///
+/// Succeeding fake submissions take as long as real ones on average.
+async fn check_fake_submission<S: KeyStore>(
+    state: &BackendState<S>,
+    data: &DailyTracingKeyStoreRequest,
+    started: Instant,
+) -> Result<usize, io::Error> {
+    if state.config().read_only {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "store is opened read-only",
+        ));
+    }
+    check_submission_days(state, &data.keys)?;
+    if state.federation.is_some() {
+        validate_regions(&data.visited_countries)?;
+    }
+    let remaining = state
+        .submission_timer
+        .average()
+        .saturating_sub(started.elapsed());
+    tokio::time::delay_for(remaining).await;
+    Ok(0)
+}
+
+/// Returns the metrics label for a failed submission.
+fn rejection_reason(err: &io::Error) -> &'static str {
+    match err.kind() {
+        io::ErrorKind::InvalidInput => "invalid_input",
+        io::ErrorKind::PermissionDenied => "read_only",
+        _ => "storage_error",
+    }
+}
+
+/// Records the status and latency of a fetch request.
+async fn timed_fetch<F>(format: ResponseFormat, f: F) -> Result<Response<Body>, Rejection>
+where
+    F: Future<Output = Result<Response<Body>, Rejection>>,
+{
+    let started = Instant::now();
+    let rv = f.await;
+    let status = match rv {
+        Ok(ref res) => res.status(),
+        Err(ref rejection) => match rejection.find::<ApiError>() {
+            Some(api_error) => api_error.status(),
+            None if rejection.is_not_found() => StatusCode::NOT_FOUND,
+            None => StatusCode::BAD_REQUEST,
+        },
+    };
+    METRICS.fetch_request(format.name(), status.as_u16(), started.elapsed());
+    rv
+}
+
+/// Converts a timestamp from a request.
+fn parse_timestamp(ts: u64) -> Result<DateTime<Utc>, Rejection> {
+    i64::try_from(ts)
+        .ok()
+        .and_then(|ts| NaiveDateTime::from_timestamp_opt(ts, 0))
+        .map(|ts| Utc.from_utc_datetime(&ts))
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid timestamp").reject())
+}
 
+/// Replies with the keys of all days since a timestamp.
+async fn fetch_reply<S: KeyStore, T: KeyStore>(
+    state: &BackendState<S>,
+    store: &AsyncKeyStore<T>,
+    ts: u64,
+    format: ResponseFormat,
+    conditions: CacheConditions,
+) -> Result<Response<Body>, Rejection> {
+    timed_fetch(format, async {
+        let ts = parse_timestamp(ts)?;
+        let days = store.day_range(ts).map_err(reject_io)?;
+        let info = store.cache_info(days.clone()).await.map_err(reject_io)?;
+        cached_api_reply(format, &info, state.max_age(&info), &conditions, || async {
+            store.fetch_range(days).await.map_err(reject_io)
+        })
+        .await
+    })
+    .await
+}
+
+/// Replies with a page of keys after a cursor.
+async fn fetch_since_reply<S: KeyStore, T: KeyStore>(
+    state: &BackendState<S>,
+    store: &AsyncKeyStore<T>,
+    query: FetchSinceQuery,
+    format: ResponseFormat,
+) -> Result<Response<Body>, Rejection> {
+    timed_fetch(format, async {
+        let page = store
+            .fetch_since(query.since, state.config().fetch_page_size)
+            .await
+            .map_err(reject_io)?;
+        Ok(api_reply(format, page).into_response())
+    })
+    .await
+}
+
+/// Replies with the list of retained days.
+async fn list_days_reply<S: KeyStore, T: KeyStore>(
+    state: &BackendState<S>,
+    store: &AsyncKeyStore<T>,
+    format: ResponseFormat,
+    conditions: CacheConditions,
+) -> Result<Response<Body>, Rejection> {
+    timed_fetch(format, async {
+        let info = store
+            .cache_info(store.retained_days())
+            .await
+            .map_err(reject_io)?;
+        cached_api_reply(format, &info, state.max_age(&info), &conditions, || async {
+            store.list_days().await.map_err(reject_io)
+        })
+        .await
+    })
+    .await
+}
+
+/// Replies with the keys of a single day.
+async fn fetch_day_reply<S: KeyStore, T: KeyStore>(
+    state: &BackendState<S>,
+    store: &AsyncKeyStore<T>,
+    day: u32,
+    format: ResponseFormat,
+    conditions: CacheConditions,
+) -> Result<Response<Body>, Rejection> {
+    timed_fetch(format, async {
+        if !store.retained_days().contains(&day) {
+            return Err(warp::reject::not_found());
+        }
+        let info = store.cache_info(day..=day).await.map_err(reject_io)?;
+        cached_api_reply(format, &info, state.max_age(&info), &conditions, || async {
+            store.fetch_day(day).await.map_err(reject_io)
+        })
+        .await
+    })
+    .await
+}
+
+/// Resolves when the process is asked to terminate.
+async fn shutdown_signal() -> Result<(), io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        rv = tokio::signal::ctrl_c() => rv?,
+    }
+    Ok(())
+}
+
+/// Reloads the config whenever the process receives `SIGHUP`.
+async fn reload_on_hangup<S, F>(state: Arc<BackendState<S>>, mut hangups: Signal, reload_config: F)
+where
+    S: KeyStore,
+    F: Fn() -> Result<Config, io::Error>,
+{
+    while hangups.recv().await.is_some() {
+        match reload_config().and_then(|config| state.reload(config)) {
+            Ok(()) => log::info!("reloaded config and signing keys"),
+            Err(err) => log::error!("failed to reload config, keeping the old one: {}", err),
+        }
+    }
+}
+
+/// Spawns a background task that is stopped on shutdown.
+fn spawn_task<F>(tasks: &mut Vec<AbortHandle>, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (task, handle) = abortable(task);
+    tokio::spawn(task);
+    tasks.push(handle);
+}
+
+/// Builds the API routes for a connection.
+fn routes<S: KeyStore>(
+    backend_state: Arc<BackendState<S>>,
+    connection: Connection,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     macro_rules! pass_state {
         () => {
             warp::any().map({
@@ -34,38 +503,590 @@ pub async fn serve() {
         };
     }
 
-    let make_svc = make_service_fn(move |_| {
-        let fetch = warp::path("fetch")
-            .and(warp::path::param())
+    let max_body_size = backend_state.config().limits.max_body_size;
+    let max_federation_body_size = backend_state
+        .config()
+        .federation
+        .as_ref()
+        .map_or(0, |federation| federation.max_body_size);
+
+    let client_ip = warp::header::optional::<String>("x-forwarded-for")
+        .and(pass_state!())
+        .map(move |forwarded_for, state: Arc<BackendState<S>>| {
+            client_ip(connection.remote, forwarded_for, &state.config().limits)
+        });
+
+    let fetch_limit = client_ip
+        .clone()
+        .and(pass_state!())
+        .and_then(|ip: IpAddr, state: Arc<BackendState<S>>| async move {
+            state.limits.check_fetch(ip).map_err(rate_limited)
+        })
+        .untuple_one();
+
+    let fetch = warp::path("fetch")
+        .and(warp::path::param())
+        .and(fetch_limit.clone())
+        .and(response_format())
+        .and(cache_conditions())
+        .and(pass_state!())
+        .and_then(
+            |ts: u64,
+             format: ResponseFormat,
+             conditions: CacheConditions,
+             state: Arc<BackendState<S>>| async move {
+                fetch_reply(&state, &state.store, ts, format, conditions).await
+            },
+        );
+
+    let list_days =
+        warp::path("days")
+            .and(warp::path::end())
+            .and(fetch_limit.clone())
+            .and(response_format())
+            .and(cache_conditions())
             .and(pass_state!())
-            .map(|ts: u64, state: Arc<BackendState>| {
-                let ts = Utc.from_utc_datetime(&NaiveDateTime::from_timestamp(ts as i64, 0));
-                state.store.fetch_buckets(ts).unwrap()
-            })
-            .map(api_reply);
+            .and_then(
+                |format: ResponseFormat,
+                 conditions: CacheConditions,
+                 state: Arc<BackendState<S>>| async move {
+                    list_days_reply(&state, &state.store, format, conditions).await
+                },
+            );
 
-        let submit = warp::path("submit")
-            .and(warp::body::json())
+    let fetch_day = warp::path("days")
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(fetch_limit.clone())
+        .and(response_format())
+        .and(cache_conditions())
+        .and(pass_state!())
+        .and_then(
+            |day: u32,
+             format: ResponseFormat,
+             conditions: CacheConditions,
+             state: Arc<BackendState<S>>| async move {
+                fetch_day_reply(&state, &state.store, day, format, conditions).await
+            },
+        );
+
+    let fetch_since =
+        warp::path("fetch")
+            .and(warp::path::end())
+            .and(fetch_limit.clone())
+            .and(warp::query())
+            .and(response_format())
             .and(pass_state!())
-            .map(
-                |data: DailyTracingKeyStoreRequest, state: Arc<BackendState>| {
-                    for (day_num, key) in data.keys {
-                        state.store.add_daily_tracing_key(day_num, key).unwrap();
-                    }
+            .and_then(
+                |query: FetchSinceQuery,
+                 format: ResponseFormat,
+                 state: Arc<BackendState<S>>| async move {
+                    fetch_since_reply(&state, &state.store, query, format).await
                 },
-            )
-            .map(api_reply);
+            );
+
+    let region_fetch = warp::path!("fetch" / Region / u64)
+        .and(fetch_limit.clone())
+        .and(response_format())
+        .and(cache_conditions())
+        .and(pass_state!())
+        .and_then(
+            |region: Region,
+             ts: u64,
+             format: ResponseFormat,
+             conditions: CacheConditions,
+             state: Arc<BackendState<S>>| async move {
+                let store = state.partition(&region)?;
+                fetch_reply(&state, &store, ts, format, conditions).await
+            },
+        );
+
+    let region_list_days = warp::path!("fetch" / Region / "days")
+        .and(fetch_limit.clone())
+        .and(response_format())
+        .and(cache_conditions())
+        .and(pass_state!())
+        .and_then(
+            |region: Region,
+             format: ResponseFormat,
+             conditions: CacheConditions,
+             state: Arc<BackendState<S>>| async move {
+                let store = state.partition(&region)?;
+                list_days_reply(&state, &store, format, conditions).await
+            },
+        );
+
+    let region_fetch_day = warp::path!("fetch" / Region / "days" / u32)
+        .and(fetch_limit.clone())
+        .and(response_format())
+        .and(cache_conditions())
+        .and(pass_state!())
+        .and_then(
+            |region: Region,
+             day: u32,
+             format: ResponseFormat,
+             conditions: CacheConditions,
+             state: Arc<BackendState<S>>| async move {
+                let store = state.partition(&region)?;
+                fetch_day_reply(&state, &store, day, format, conditions).await
+            },
+        );
+
+    let region_fetch_since = warp::path!("fetch" / Region)
+        .and(fetch_limit.clone())
+        .and(warp::query())
+        .and(response_format())
+        .and(pass_state!())
+        .and_then(
+            |region: Region,
+             query: FetchSinceQuery,
+             format: ResponseFormat,
+             state: Arc<BackendState<S>>| async move {
+                let store = state.partition(&region)?;
+                fetch_since_reply(&state, &store, query, format).await
+            },
+        );
+
+    // admin, replication and federation endpoints
+    macro_rules! authorized {
+        ($access:expr) => {
+            warp::header::optional::<String>("authorization")
+                .and(pass_state!())
+                .and_then(
+                    move |authorization: Option<String>, state: Arc<BackendState<S>>| async move {
+                        let config = state.config();
+                        check_access(connection, $access.token(&config), authorization)
+                    },
+                )
+                .untuple_one()
+        };
+    }
+    let admin_auth = authorized!(Access::Admin);
+    let federation_auth = authorized!(Access::Federation);
 
-        let routes = response_format().and(fetch.or(submit));
-        let svc = warp::service(routes);
-        async move { Ok::<_, Infallible>(svc) }
+    let submit_limit = client_ip
+        .and(warp::header::optional::<String>("x-upload-token"))
+        .and(pass_state!())
+        .and_then(
+            |ip: IpAddr, token: Option<String>, state: Arc<BackendState<S>>| async move {
+                state
+                    .limits
+                    .check_submit(ip, token.as_deref())
+                    .map_err(|retry_after| {
+                        METRICS.submission_rejected("rate_limited");
+                        rate_limited(retry_after)
+                    })
+            },
+        )
+        .untuple_one();
+
+    let submit = warp::path("submit")
+        .and(submit_limit)
+        .and(warp::header::optional("content-type"))
+        .and(limited_body(max_body_size))
+        .and(response_format())
+        .and(pass_state!())
+        .and_then(
+            |content_type: Option<String>,
+             body: Bytes,
+             format: ResponseFormat,
+             state: Arc<BackendState<S>>| async move {
+                let started = Instant::now();
+                let mut data: DailyTracingKeyStoreRequest =
+                    decode_body(content_type.as_deref(), &body).map_err(|err| {
+                        METRICS.submission_rejected("invalid_body");
+                        err.reject()
+                    })?;
+                data.strip_padding(body.len()).map_err(|err| {
+                    METRICS.submission_rejected("invalid_body");
+                    reject_io(err)
+                })?;
+                if state.replica.is_some() {
+                    METRICS.submission_rejected("replica");
+                    return Err(ApiError::new(
+                        StatusCode::FORBIDDEN,
+                        "this is a replica, submit to the primary",
+                    )
+                    .reject());
+                }
+                let fake = data.fake;
+                let rv = if fake {
+                    check_fake_submission(&state, &data, started).await
+                } else {
+                    store_submission(&state, data).await
+                };
+                match rv {
+                    Ok(_) if fake => METRICS.submission_accepted(0),
+                    Ok(added) => {
+                        state.submission_timer.record(started.elapsed());
+                        METRICS.submission_accepted(added);
+                    }
+                    Err(err) => {
+                        METRICS.submission_rejected(rejection_reason(&err));
+                        return Err(reject_io(err));
+                    }
+                }
+                Ok::<_, Rejection>(api_reply(format, SubmitResponse::padded()))
+            },
+        );
+
+    let healthz = warp::path("healthz")
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&HealthStatus { status: "ok" }));
+
+    let readyz = warp::path("readyz")
+        .and(warp::path::end())
+        .and(pass_state!())
+        .and_then(|state: Arc<BackendState<S>>| async move {
+            let config = state.config();
+            let writable_path = if config.read_only || config.backend == StoreBackend::Memory {
+                None
+            } else {
+                Some(config.db_path.as_path())
+            };
+            let readiness = check_readiness(&state.store, writable_path).await;
+            let status = if readiness.ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::json(&readiness),
+                status,
+            ))
+        });
+
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(admin_auth.clone())
+        .and(pass_state!())
+        .and_then(|state: Arc<BackendState<S>>| async move {
+            let days = state.store.list_days().await.map_err(reject_io)?;
+            let db_path = state.config().db_path.clone();
+            let disk_usage = task::spawn_blocking(move || disk_usage(&db_path))
+                .await
+                .map_err(|err| reject_io(io::Error::other(err)))?
+                .map_err(reject_io)?;
+            Ok::<_, Rejection>(warp::reply::with_header(
+                METRICS.render(&days, disk_usage),
+                CONTENT_TYPE,
+                "text/plain; version=0.0.4",
+            ))
+        });
+
+    let snapshot = warp::path("snapshot")
+        .and(warp::path::end())
+        .and(admin_auth.clone())
+        .and(fetch_limit.clone())
+        .and(pass_state!())
+        .and_then(|state: Arc<BackendState<S>>| async move {
+            let partitions = state.partitions.clone();
+            let mut snapshot = state
+                .store
+                .run(move |store| {
+                    let mut snapshot = Snapshot::capture(store)?;
+                    snapshot.capture_partitions(&partitions)?;
+                    Ok(snapshot)
+                })
+                .await
+                .map_err(reject_io)?;
+            // tags are written before their keys so taking them after the
+            // keys gives every key of another region its tag
+            if let Some(ref federation) = state.federation {
+                snapshot.tags = federation.tag_records();
+            }
+            let zip = snapshot.to_zip().map_err(reject_io)?;
+            Ok::<_, Rejection>(warp::reply::with_header(
+                zip,
+                CONTENT_TYPE,
+                "application/zip",
+            ))
+        });
+
+    let export = warp::path("export")
+        .and(warp::path::param())
+        .and(fetch_limit.clone())
+        .and(pass_state!())
+        .and_then(|ts: u64, state: Arc<BackendState<S>>| async move {
+            let config = state.config();
+            let (signer, export_config) = match (state.export_signer(), &config.export) {
+                (Some(signer), Some(export_config)) => (signer, export_config),
+                _ => return Err(warp::reject::not_found()),
+            };
+            let start_timestamp = parse_timestamp(ts)?;
+            let days = state.store.day_range(start_timestamp).map_err(reject_io)?;
+            let archive = ExportArchive {
+                region: export_config.region.clone(),
+                start_timestamp,
+                end_timestamp: Utc::now(),
+                keys: state.store.fetch_range(days).await.map_err(reject_io)?,
+            };
+            Ok(warp::reply::with_header(
+                archive.to_zip(&signer).map_err(reject_io)?,
+                CONTENT_TYPE,
+                "application/zip",
+            ))
+        });
+
+    let replication_stream = warp::path!("replication" / "stream")
+        .and(authorized!(Access::Replication))
+        .and(fetch_limit.clone())
+        .and(warp::query())
+        .and(pass_state!())
+        .and_then(
+            |query: FetchSinceQuery, state: Arc<BackendState<S>>| async move {
+                let config = state.config();
+                let stream = stream_keys(
+                    state.store.clone(),
+                    query.since,
+                    &config.replication,
+                    state.shutdown.clone(),
+                );
+                Ok::<_, Rejection>(
+                    Response::builder()
+                        .header(CONTENT_TYPE, "application/x-ndjson")
+                        .body(Body::wrap_stream(stream))
+                        .unwrap(),
+                )
+            },
+        );
+
+    let replication_status = warp::path!("replication" / "status")
+        .and(admin_auth)
+        .and(pass_state!())
+        .map(|state: Arc<BackendState<S>>| {
+            let status = match state.replica {
+                Some(ref replica) => ReplicationStatus::Replica(replica.status()),
+                None => ReplicationStatus::Primary,
+            };
+            warp::reply::json(&status)
+        });
+
+    let federation_batches = warp::path!("federation" / "batches")
+        .and(federation_auth.clone())
+        .and(warp::get())
+        .and(warp::query())
+        .and(pass_state!())
+        .and_then(
+            |query: FetchSinceQuery, state: Arc<BackendState<S>>| async move {
+                let federation = state
+                    .federation
+                    .as_ref()
+                    .ok_or_else(warp::reject::not_found)?;
+                let batch = federation
+                    .local_batch(query.since)
+                    .await
+                    .map_err(reject_io)?;
+                let signed = federation.sign_batch(&batch).map_err(reject_io)?;
+                Ok::<_, Rejection>(warp::reply::json(&signed))
+            },
+        );
+
+    let federation_upload = warp::path!("federation" / "upload")
+        .and(federation_auth)
+        .and(warp::post())
+        .and(limited_body(max_federation_body_size))
+        .and(pass_state!())
+        .and_then(|body: Bytes, state: Arc<BackendState<S>>| async move {
+            let federation = state
+                .federation
+                .as_ref()
+                .ok_or_else(warp::reject::not_found)?;
+            let signed: SignedBatch =
+                decode_body(Some("application/json"), &body).map_err(ApiError::reject)?;
+            let added = federation.receive_batch(&signed).await.map_err(reject_io)?;
+            Ok::<_, Rejection>(warp::reply::json(&UploadResponse { added }))
+        });
+
+    fetch_since
+        .or(fetch)
+        .or(list_days)
+        .or(fetch_day)
+        .or(region_fetch_since)
+        .or(region_fetch)
+        .or(region_list_days)
+        .or(region_fetch_day)
+        .or(submit)
+        .or(metrics)
+        .or(snapshot)
+        .or(healthz)
+        .or(readyz)
+        .or(export)
+        .or(replication_stream)
+        .or(replication_status)
+        .or(federation_batches)
+        .or(federation_upload)
+        .recover(handle_rejection)
+}
+
+/// Opens the configured store and serves the API.
+///
+/// `reload_config` is called to load the config again on `SIGHUP`.
+pub async fn serve<F>(config: Config, reload_config: F) -> Result<(), io::Error>
+where
+    F: Fn() -> Result<Config, io::Error> + Send + Sync + 'static,
+{
+    let store = config.open_store()?;
+    serve_store(config, store, reload_config).await
+}
+
+/// Serves the API on top of the given store.
+///
+/// On `SIGTERM` or `SIGINT` the server stops accepting connections, ends
+/// the replication streams, waits for all in-flight requests, stops the
+/// background tasks and flushes the store before it returns.
+pub async fn serve_store<S, F>(config: Config, store: S, reload_config: F) -> Result<(), io::Error>
+where
+    S: KeyStore,
+    F: Fn() -> Result<Config, io::Error> + Send + Sync + 'static,
+{
+    check_auth(&config)?;
+    let export_signer = config
+        .export
+        .as_ref()
+        .map(|export| ExportSigner::from_config(export).map(Arc::new))
+        .transpose()?;
+    let replica = config
+        .replication
+        .primary
+        .as_ref()
+        .map(|primary| Arc::new(Replica::new(primary)));
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|tls| TlsServer::new(tls).map(Arc::new))
+        .transpose()?;
+    let client = http_client(config.tls.as_ref())?;
+    let store = AsyncKeyStore::new(store);
+    let partitions = Arc::new(config.open_partitions()?);
+    // replicas get the keys of other regions through their primary
+    let federation = match (&config.federation, &replica) {
+        (Some(federation_config), None) => Some(Arc::new(Federation::open(
+            federation_config.clone(),
+            client.clone(),
+            store.clone(),
+            partitions.clone(),
+            &config.federation_path(),
+        )?)),
+        _ => None,
+    };
+    let listen = config.listen;
+    let limits = RateLimits::new(&config.limits);
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let backend_state = Arc::new(BackendState {
+        limits,
+        submission_timer: SubmissionTimer::default(),
+        tls,
+        store,
+        partitions,
+        export_signer: RwLock::new(export_signer),
+        replica,
+        federation,
+        config: RwLock::new(Arc::new(config)),
+        shutdown: shutdown_receiver,
     });
 
-    let mut listenfd = listenfd::ListenFd::from_env();
-    let server = if let Some(l) = listenfd.take_tcp_listener(0).unwrap() {
-        Server::from_tcp(l).unwrap()
-    } else {
-        Server::bind(&([127, 0, 0, 1], 5000).into())
+    let mut tasks = vec![];
+    spawn_task(
+        &mut tasks,
+        reload_on_hangup(
+            backend_state.clone(),
+            signal(SignalKind::hangup())?,
+            reload_config,
+        ),
+    );
+
+    if let Some(ref federation) = backend_state.federation {
+        spawn_task(&mut tasks, run_federation(federation.clone()));
+    }
+
+    let config = backend_state.config();
+    if let Some(ref replica) = backend_state.replica {
+        spawn_task(
+            &mut tasks,
+            run_replica(
+                backend_state.store.clone(),
+                config.replication.clone(),
+                replica.clone(),
+                client,
+            ),
+        );
+    }
+
+    // the writable instance takes care of retention
+    if !config.read_only {
+        spawn_task(
+            &mut tasks,
+            run_retention(
+                backend_state.store.clone(),
+                backend_state.partitions.clone(),
+                config.retention.clone(),
+            ),
+        );
+    }
+
+    let state = backend_state.clone();
+
+    let listener = match listenfd::ListenFd::from_env().take_tcp_listener(0)? {
+        Some(listener) => listener,
+        None => StdTcpListener::bind(listen)?,
+    };
+    let shutdown = async {
+        if let Err(err) = shutdown_signal().await {
+            log::error!("failed to wait for shutdown signals: {}", err);
+            futures::future::pending::<()>().await;
+        }
+        log::info!("shutting down, waiting for in-flight requests");
+        // replication streams never end on their own
+        shutdown_sender.broadcast(true).ok();
     };
-    server.serve(make_svc).await.unwrap();
+    match state.tls {
+        Some(ref tls) => {
+            let incoming = tls.clone().incoming(listener)?;
+            let backend_state = state.clone();
+            let make_svc = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                let connection = Connection {
+                    remote: conn
+                        .get_ref()
+                        .0
+                        .peer_addr()
+                        .map_or(Ipv4Addr::UNSPECIFIED.into(), |addr| addr.ip()),
+                    client_authenticated: is_client_authenticated(conn),
+                };
+                let svc = warp::service(routes(backend_state.clone(), connection));
+                async move { Ok::<_, Infallible>(svc) }
+            });
+            Server::builder(accept::from_stream(incoming))
+                .serve(make_svc)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        None => {
+            let backend_state = state.clone();
+            let make_svc = make_service_fn(move |conn: &AddrStream| {
+                let connection = Connection {
+                    remote: conn.remote_addr().ip(),
+                    client_authenticated: false,
+                };
+                let svc = warp::service(routes(backend_state.clone(), connection));
+                async move { Ok::<_, Infallible>(svc) }
+            });
+            Server::from_tcp(listener)
+                .map_err(io::Error::other)?
+                .serve(make_svc)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    }
+    .map_err(io::Error::other)?;
+
+    for task in tasks {
+        task.abort();
+    }
+    state.store.run(|store| store.flush()).await?;
+    for (_, partition) in state.partitions.all() {
+        partition.run(|store| store.flush()).await?;
+    }
+    log::info!("flushed the store, exiting");
+    Ok(())
 }
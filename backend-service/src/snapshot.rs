@@ -0,0 +1,336 @@
+//! Point-in-time snapshots of a key store.
+//!
+//! A snapshot is a zip file with two members: `keys.bin` which holds one
+//! record per key (sequence number as u64, day as u32 and the 16 byte key,
+//! all little endian) in sequence order and `manifest.json` which describes
+//! the snapshot and holds a SHA-256 checksum over `keys.bin`.  Stores with
+//! federation additionally have a `tags.jsonl` member with the tags of keys
+//! from other regions, which is covered by a second checksum.  The keys of
+//! every region partition are in a `regions/<region>.bin` member in the
+//! format of `keys.bin` with their own checksum.
+//!
+//! Snapshots do not depend on the storage backend, so restoring one into a
+//! different backend moves all keys over with their sequence numbers.
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use contact_tracing::DailyTracingKey;
+
+use crate::federation::{restore_tags, TagRecord};
+use crate::store::{DayInfo, KeyStore, Partitions, Region};
+
+/// The current version of the snapshot format.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The size of a record in `keys.bin`.
+const RECORD_SIZE: usize = 28;
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Describes the contents of a snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    /// The version of the snapshot format.
+    pub version: u32,
+    /// When the snapshot was taken in seconds since the epoch.
+    pub created: u64,
+    /// The number of keys in the snapshot.
+    pub count: usize,
+    /// The highest sequence number in the snapshot.
+    pub max_seq: u64,
+    /// The number of keys per day.
+    pub days: Vec<DayInfo>,
+    /// The hex encoded SHA-256 checksum of `keys.bin`.
+    pub checksum: String,
+    /// The number of federation tags.
+    #[serde(default)]
+    pub tag_count: usize,
+    /// The hex encoded SHA-256 checksum of `tags.jsonl` if there are tags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags_checksum: Option<String>,
+    /// The region partitions in the snapshot.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub partitions: BTreeMap<Region, PartitionManifest>,
+}
+
+/// Describes the keys of a region partition in a snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PartitionManifest {
+    /// The number of keys in the partition.
+    pub count: usize,
+    /// The hex encoded SHA-256 checksum of `regions/<region>.bin`.
+    pub checksum: String,
+}
+
+/// A point-in-time copy of all keys of a store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// When the snapshot was taken in seconds since the epoch.
+    pub created: u64,
+    /// All keys as `(seq, day, key)` in sequence order.
+    pub entries: Vec<(u64, u32, DailyTracingKey)>,
+    /// The federation tags of the keys.
+    pub tags: Vec<TagRecord>,
+    /// The keys of the region partitions as `(seq, day, key)`.
+    pub partitions: BTreeMap<Region, Vec<(u64, u32, DailyTracingKey)>>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of a store.
+    pub fn capture<S: KeyStore + ?Sized>(store: &S) -> Result<Snapshot, io::Error> {
+        Ok(Snapshot {
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            entries: store.dump()?,
+            tags: vec![],
+            partitions: BTreeMap::new(),
+        })
+    }
+
+    /// Adds the keys of all region partitions to the snapshot.
+    pub fn capture_partitions(&mut self, partitions: &Partitions) -> Result<(), io::Error> {
+        for (region, store) in partitions.all() {
+            self.partitions.insert(region, store.inner().dump()?);
+        }
+        Ok(())
+    }
+
+    /// Returns the manifest describing the snapshot.
+    pub fn manifest(&self) -> SnapshotManifest {
+        self.manifest_for(&encode_entries(&self.entries), &self.encode_tags())
+    }
+
+    fn manifest_for(&self, keys: &[u8], tags: &[u8]) -> SnapshotManifest {
+        let mut days = BTreeMap::new();
+        for &(_, day, _) in &self.entries {
+            *days.entry(day).or_insert(0) += 1;
+        }
+        SnapshotManifest {
+            version: SNAPSHOT_VERSION,
+            created: self.created,
+            count: self.entries.len(),
+            max_seq: self.entries.last().map_or(0, |&(seq, _, _)| seq),
+            days: days
+                .into_iter()
+                .map(|(day, count)| DayInfo { day, count })
+                .collect(),
+            checksum: hex_digest(keys),
+            tag_count: self.tags.len(),
+            tags_checksum: if self.tags.is_empty() {
+                None
+            } else {
+                Some(hex_digest(tags))
+            },
+            partitions: self
+                .partitions
+                .iter()
+                .map(|(region, entries)| {
+                    let manifest = PartitionManifest {
+                        count: entries.len(),
+                        checksum: hex_digest(&encode_entries(entries)),
+                    };
+                    (region.clone(), manifest)
+                })
+                .collect(),
+        }
+    }
+
+    fn encode_tags(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for record in &self.tags {
+            serde_json::to_writer(&mut buf, record).unwrap();
+            buf.push(b'\n');
+        }
+        buf
+    }
+
+    /// Writes the snapshot as zip archive.
+    pub fn to_zip(&self) -> Result<Vec<u8>, io::Error> {
+        let keys = encode_entries(&self.entries);
+        let tags = self.encode_tags();
+        let manifest = serde_json::to_vec_pretty(&self.manifest_for(&keys, &tags))?;
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("manifest.json", options)?;
+        zip.write_all(&manifest)?;
+        zip.start_file("keys.bin", options)?;
+        zip.write_all(&keys)?;
+        if !self.tags.is_empty() {
+            zip.start_file("tags.jsonl", options)?;
+            zip.write_all(&tags)?;
+        }
+        for (region, entries) in &self.partitions {
+            zip.start_file(partition_member(region), options)?;
+            zip.write_all(&encode_entries(entries))?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Parses a zip archive and verifies it against its manifest.
+    pub fn from_zip(data: &[u8]) -> Result<Snapshot, io::Error> {
+        let mut zip = ZipArchive::new(Cursor::new(data))?;
+        let mut manifest = vec![];
+        zip.by_name("manifest.json")?.read_to_end(&mut manifest)?;
+        let mut keys = vec![];
+        zip.by_name("keys.bin")?.read_to_end(&mut keys)?;
+
+        let manifest: SnapshotManifest = serde_json::from_slice(&manifest).map_err(invalid_data)?;
+        if manifest.version != SNAPSHOT_VERSION {
+            return Err(invalid_data("unsupported snapshot version"));
+        }
+        if hex_digest(&keys) != manifest.checksum {
+            return Err(invalid_data("bad checksum, corrupted snapshot"));
+        }
+        let entries = decode_entries(&keys)?;
+        let mut tags = vec![];
+        if let Some(ref checksum) = manifest.tags_checksum {
+            zip.by_name("tags.jsonl")?.read_to_end(&mut tags)?;
+            if hex_digest(&tags) != *checksum {
+                return Err(invalid_data("bad checksum, corrupted snapshot"));
+            }
+        }
+        let tag_records = tags
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).map_err(invalid_data))
+            .collect::<Result<_, _>>()?;
+        let mut partitions = BTreeMap::new();
+        for (region, partition) in &manifest.partitions {
+            let mut data = vec![];
+            zip.by_name(&partition_member(region))?
+                .read_to_end(&mut data)?;
+            if hex_digest(&data) != partition.checksum {
+                return Err(invalid_data("bad checksum, corrupted snapshot"));
+            }
+            partitions.insert(region.clone(), decode_entries(&data)?);
+        }
+
+        let snapshot = Snapshot {
+            created: manifest.created,
+            entries,
+            tags: tag_records,
+            partitions,
+        };
+        if snapshot.manifest_for(&keys, &tags) != manifest {
+            return Err(invalid_data("snapshot does not match its manifest"));
+        }
+        Ok(snapshot)
+    }
+
+    /// Restores the snapshot into an empty store.
+    ///
+    /// Returns the number of keys that were restored.
+    pub fn restore<S: KeyStore + ?Sized>(&self, store: &S) -> Result<usize, io::Error> {
+        check_empty(store)?;
+        let mut restored = 0;
+        for &(seq, day, key) in &self.entries {
+            if store.import_key(seq, day, key)? {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Restores the snapshot and its tags into an empty store with
+    /// federation.
+    ///
+    /// The tags go into the federation state in `state_path` before the
+    /// keys so that keys of other regions are never taken for local ones.
+    pub fn restore_federated<S: KeyStore + ?Sized>(
+        &self,
+        store: &S,
+        state_path: &Path,
+    ) -> Result<usize, io::Error> {
+        check_empty(store)?;
+        restore_tags(state_path, &self.tags)?;
+        self.restore(store)
+    }
+
+    /// Restores the region partitions, which all need to be empty.
+    ///
+    /// Returns the number of keys that were restored.
+    pub fn restore_partitions(&self, partitions: &Partitions) -> Result<usize, io::Error> {
+        let mut stores = vec![];
+        for (region, entries) in &self.partitions {
+            let store = partitions.open(region)?;
+            check_empty(&**store.inner())?;
+            stores.push((store, entries));
+        }
+        let mut restored = 0;
+        for (store, entries) in stores {
+            for &(seq, day, key) in entries {
+                if store.inner().import_key(seq, day, key)? {
+                    restored += 1;
+                }
+            }
+        }
+        Ok(restored)
+    }
+}
+
+/// Returns the name of the zip member with the keys of a region.
+fn partition_member(region: &Region) -> String {
+    format!("regions/{}.bin", region)
+}
+
+fn encode_entries(entries: &[(u64, u32, DailyTracingKey)]) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(entries.len() * RECORD_SIZE);
+    for (seq, day, key) in entries {
+        buf.put_u64_le(*seq);
+        buf.put_u32_le(*day);
+        buf.put_slice(key.as_bytes());
+    }
+    buf.to_vec()
+}
+
+fn decode_entries(data: &[u8]) -> Result<Vec<(u64, u32, DailyTracingKey)>, io::Error> {
+    if !data.len().is_multiple_of(RECORD_SIZE) {
+        return Err(invalid_data("truncated snapshot"));
+    }
+    let mut last_seq = 0;
+    let mut entries = Vec::with_capacity(data.len() / RECORD_SIZE);
+    for mut record in data.chunks_exact(RECORD_SIZE) {
+        let seq = record.get_u64_le();
+        let day = record.get_u32_le();
+        let key = DailyTracingKey::from_bytes(record).map_err(invalid_data)?;
+        if seq <= last_seq {
+            return Err(invalid_data("snapshot is not in sequence order"));
+        }
+        last_seq = seq;
+        entries.push((seq, day, key));
+    }
+    Ok(entries)
+}
+
+fn check_empty<S: KeyStore + ?Sized>(store: &S) -> Result<(), io::Error> {
+    if !store.dump()?.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can only restore into an empty store",
+        ));
+    }
+    Ok(())
+}
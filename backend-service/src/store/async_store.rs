@@ -0,0 +1,139 @@
+use std::io;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use tokio::task;
+
+use contact_tracing::DailyTracingKey;
+
+use crate::health::{is_storage_failure, Health};
+use crate::store::{CacheInfo, DayInfo, DayKeys, EntryPage, KeyPage, KeyStore};
+
+/// Async interface to a [`KeyStore`].
+///
+/// All store operations can block on disk I/O or locks, so they are moved
+/// to tokio's blocking thread pool instead of running on the reactor.
+#[derive(Debug)]
+pub struct AsyncKeyStore<S> {
+    store: Arc<S>,
+    health: Arc<Health>,
+}
+
+impl<S> Clone for AsyncKeyStore<S> {
+    fn clone(&self) -> AsyncKeyStore<S> {
+        AsyncKeyStore {
+            store: self.store.clone(),
+            health: self.health.clone(),
+        }
+    }
+}
+
+impl<S: KeyStore> AsyncKeyStore<S> {
+    /// Wraps a store.
+    pub fn new(store: S) -> AsyncKeyStore<S> {
+        AsyncKeyStore::from_arc(Arc::new(store))
+    }
+
+    /// Wraps an already shared store.
+    pub fn from_arc(store: Arc<S>) -> AsyncKeyStore<S> {
+        AsyncKeyStore {
+            store,
+            health: Arc::default(),
+        }
+    }
+
+    /// Returns the underlying store.
+    pub fn inner(&self) -> &Arc<S> {
+        &self.store
+    }
+
+    /// Returns the health of the store.
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Runs a function with the store on the blocking thread pool.
+    ///
+    /// Storage failures are recorded for the readiness check.
+    pub async fn run<F, R>(&self, f: F) -> Result<R, io::Error>
+    where
+        F: FnOnce(&S) -> Result<R, io::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        let store = self.store.clone();
+        let rv = task::spawn_blocking(move || f(&store))
+            .await
+            .map_err(io::Error::other)?;
+        if let Err(ref err) = rv {
+            if is_storage_failure(err) {
+                self.health.record_io_error(err);
+            }
+        }
+        rv
+    }
+
+    /// Adds tracing keys and returns how many of them were new.
+    pub async fn add_keys(&self, keys: Vec<(u32, DailyTracingKey)>) -> Result<usize, io::Error> {
+        self.run(move |store| {
+            let mut added = 0;
+            for (day, key) in keys {
+                if store.add_key(day, key)? {
+                    added += 1;
+                }
+            }
+            Ok(added)
+        })
+        .await
+    }
+
+    /// Returns the range of days to fetch for a timestamp.
+    pub fn day_range(&self, timestamp: DateTime<Utc>) -> Result<RangeInclusive<u32>, io::Error> {
+        self.store.day_range(timestamp)
+    }
+
+    /// Returns the range of days that are currently retained.
+    pub fn retained_days(&self) -> RangeInclusive<u32> {
+        self.store.retained_days()
+    }
+
+    /// Returns the keys of a range of days with their day numbers.
+    pub async fn fetch_range(
+        &self,
+        days: RangeInclusive<u32>,
+    ) -> Result<Vec<(u32, DailyTracingKey)>, io::Error> {
+        self.run(move |store| store.fetch_range(days)).await
+    }
+
+    /// Returns the cache validation info for a range of days.
+    pub async fn cache_info(&self, days: RangeInclusive<u32>) -> Result<CacheInfo, io::Error> {
+        self.run(move |store| store.cache_info(days)).await
+    }
+
+    /// Lists all retained days that have keys.
+    pub async fn list_days(&self) -> Result<Vec<DayInfo>, io::Error> {
+        self.run(|store| store.list_days()).await
+    }
+
+    /// Returns the keys of a single day.
+    pub async fn fetch_day(&self, day: u32) -> Result<Option<DayKeys>, io::Error> {
+        self.run(move |store| store.fetch_day(day)).await
+    }
+
+    /// Returns up to `limit` keys with their sequence numbers starting at
+    /// the given cursor.
+    pub async fn fetch_entries_since(
+        &self,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<EntryPage, io::Error> {
+        self.run(move |store| store.fetch_entries_since(cursor, limit))
+            .await
+    }
+
+    /// Returns up to `limit` keys starting at the given cursor.
+    pub async fn fetch_since(&self, cursor: u64, limit: usize) -> Result<KeyPage, io::Error> {
+        self.run(move |store| store.fetch_since(cursor, limit))
+            .await
+    }
+}
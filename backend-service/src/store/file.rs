@@ -0,0 +1,1003 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime};
+
+use bytes::{Buf, BufMut, BytesMut};
+use crc::crc32;
+use fs2::FileExt;
+
+use contact_tracing::DailyTracingKey;
+
+use crate::metrics::METRICS;
+use crate::store::segment::{decode_segment, decode_segment_header, encode_segment};
+use crate::store::{
+    check_import_seq, make_entry_page, Bucket, DayInfo, DaySummary, DedupIndex, Entry, EntryPage,
+    KeyStore, DAYS_WINDOW,
+};
+
+/// Bucket files with sequence numbers start with this header.
+const BUCKET_HEADER: &[u8; 8] = b"DTKBKT02";
+
+/// The size of a record in a bucket (sequence number, key and checksum).
+const RECORD_SIZE: usize = 28;
+
+/// The size of a record in a legacy bucket (key and checksum).
+const LEGACY_RECORD_SIZE: usize = 20;
+
+/// The name of the lock file in the store folder.
+const LOCK_FILENAME: &str = ".lock";
+
+/// Stores daily tracing keys in one append only bucket file per day.
+///
+/// A writable store holds an exclusive lock on its folder for as long as it
+/// is open so that two processes can never append to the same buckets.
+pub struct FileKeyStore {
+    path: PathBuf,
+    options: FileStoreOptions,
+    buckets: RwLock<BTreeMap<u32, BucketSlot>>,
+    index: RwLock<DedupIndex>,
+    next_seq: AtomicU64,
+    pending: Mutex<BTreeSet<u64>>,
+    stamps: Mutex<HashMap<u32, DiskStamp>>,
+    corrupt_days: Mutex<BTreeSet<u32>>,
+    _lock: Option<fs::File>,
+}
+
+/// A bucket that is loaded from disk on first access.
+type BucketSlot = Arc<RwLock<Option<Bucket>>>;
+
+/// The size and modification time of the segment and bucket file of a day.
+type DiskStamp = [Option<(u64, SystemTime)>; 2];
+
+impl fmt::Debug for FileKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileKeyStore")
+            .field("path", &self.path)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+fn checksum(seq: u64, key: &DailyTracingKey) -> u32 {
+    let mut buf = BytesMut::with_capacity(24);
+    buf.put_u64_le(seq);
+    buf.put_slice(key.as_bytes());
+    crc32::checksum_ieee(&buf)
+}
+
+fn encode_entry(buf: &mut BytesMut, entry: &Entry) {
+    buf.put_u64_le(entry.seq);
+    buf.put_slice(entry.key.as_bytes());
+    buf.put_u32_le(checksum(entry.seq, &entry.key));
+}
+
+/// Parses the day number out of a bucket filename.
+fn parse_bucket_filename(filename: &str) -> Option<u32> {
+    filename
+        .strip_prefix('_')?
+        .strip_suffix(".bucket")?
+        .parse()
+        .ok()
+}
+
+/// Parses the day number out of a segment filename.
+fn parse_segment_filename(filename: &str) -> Option<u32> {
+    filename
+        .strip_prefix('_')?
+        .strip_suffix(".segment")?
+        .parse()
+        .ok()
+}
+
+/// Parses the day number out of the filename of a bucket that was set
+/// aside as corrupt.
+fn parse_corrupt_filename(filename: &str) -> Option<u32> {
+    parse_bucket_filename(filename.strip_suffix(".corrupt")?)
+}
+
+/// Parses the day number out of a bucket or segment filename.
+fn parse_day_filename(filename: &str) -> Option<u32> {
+    parse_bucket_filename(filename).or_else(|| parse_segment_filename(filename))
+}
+
+/// Parses the keys of a legacy bucket file without sequence numbers.
+///
+/// Returns the keys and the number of corrupted records that were skipped.
+fn parse_legacy_bucket(data: &[u8]) -> (Vec<DailyTracingKey>, usize) {
+    let mut rv = vec![];
+    let mut corrupted = 0;
+    for mut record in data.chunks_exact(LEGACY_RECORD_SIZE) {
+        let key = DailyTracingKey::from_bytes(&record[..16]).unwrap();
+        record.advance(16);
+        if record.get_u32_le() == crc32::checksum_ieee(key.as_bytes()) {
+            rv.push(key);
+        } else {
+            corrupted += 1;
+        }
+    }
+    (rv, corrupted)
+}
+
+/// Parses a bucket file.
+///
+/// Records with a bad checksum are skipped and counted in the second return
+/// value.  A trailing partial record or header is ignored.
+fn parse_bucket(data: &[u8]) -> Result<(Bucket, usize), io::Error> {
+    let mut bucket = Bucket::default();
+    let mut corrupted = 0;
+    if BUCKET_HEADER.starts_with(data) {
+        return Ok((bucket, corrupted));
+    }
+    if !data.starts_with(BUCKET_HEADER) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown bucket format",
+        ));
+    }
+
+    for mut record in data[BUCKET_HEADER.len()..].chunks_exact(RECORD_SIZE) {
+        let seq = record.get_u64_le();
+        let key = DailyTracingKey::from_bytes(&record[..16]).unwrap();
+        record.advance(16);
+        if record.get_u32_le() == checksum(seq, &key) {
+            bucket.push(Entry { seq, key });
+        } else {
+            corrupted += 1;
+        }
+    }
+    Ok((bucket, corrupted))
+}
+
+/// Returns the sequence number of the last intact record in a bucket file.
+fn read_last_seq(f: &mut fs::File) -> Result<Option<u64>, io::Error> {
+    let records = (f.metadata()?.len() as usize).saturating_sub(BUCKET_HEADER.len()) / RECORD_SIZE;
+    let mut buf = [0u8; RECORD_SIZE];
+    for idx in (0..records).rev() {
+        f.seek(SeekFrom::Start(
+            (BUCKET_HEADER.len() + idx * RECORD_SIZE) as u64,
+        ))?;
+        f.read_exact(&mut buf)?;
+        let mut record = &buf[..];
+        let seq = record.get_u64_le();
+        let key = DailyTracingKey::from_bytes(&record[..16]).unwrap();
+        record.advance(16);
+        if record.get_u32_le() == checksum(seq, &key) {
+            return Ok(Some(seq));
+        }
+    }
+    Ok(None)
+}
+
+/// Truncates a partially written record at the end of a bucket file.
+///
+/// Appends are not atomic so a crash in the middle of one can leave a torn
+/// record behind which would misalign all records written after it.
+fn truncate_torn_record(path: &Path, f: &mut fs::File, fsync: bool) -> Result<(), io::Error> {
+    let len = f.metadata()?.len() as usize;
+    let valid_len = if len < BUCKET_HEADER.len() {
+        0
+    } else {
+        len - (len - BUCKET_HEADER.len()) % RECORD_SIZE
+    };
+    if valid_len != len {
+        log::warn!(
+            "truncating torn record in bucket {} ({} bytes)",
+            path.display(),
+            len - valid_len
+        );
+        f.set_len(valid_len as u64)?;
+        if fsync {
+            f.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Fsyncs a directory so that created and renamed files are durable.
+fn sync_dir(path: &Path) -> Result<(), io::Error> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// Atomically replaces a bucket file with the given entries.
+fn write_bucket(path: &Path, entries: &[Entry], fsync: bool) -> Result<(), io::Error> {
+    let mut buf = BytesMut::with_capacity(BUCKET_HEADER.len() + entries.len() * RECORD_SIZE);
+    buf.put_slice(BUCKET_HEADER);
+    for entry in entries {
+        encode_entry(&mut buf, entry);
+    }
+    write_atomic(path, &buf, fsync)
+}
+
+/// Atomically replaces a file.
+fn write_atomic(path: &Path, data: &[u8], fsync: bool) -> Result<(), io::Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut f = fs::File::create(&tmp_path)?;
+    f.write_all(data)?;
+    f.flush()?;
+    if fsync {
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    if fsync {
+        sync_dir(path.parent().unwrap_or_else(|| Path::new(".")))?;
+    }
+    Ok(())
+}
+
+/// Takes the exclusive lock on a store folder.
+fn lock_store(path: &Path) -> Result<fs::File, io::Error> {
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(LOCK_FILENAME))?;
+    lock.try_lock_exclusive().map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("store {} is locked by another process", path.display()),
+        )
+    })?;
+    Ok(lock)
+}
+
+/// Stamps a file or returns `None` if it does not exist.
+fn stamp_file(path: &Path) -> Result<Option<(u64, SystemTime)>, io::Error> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some((metadata.len(), metadata.modified()?))),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Options for opening a [`FileKeyStore`].
+#[derive(Debug, Clone)]
+pub struct FileStoreOptions {
+    /// Fsync bucket files after every write.
+    ///
+    /// Without this an acknowledged key can be lost if the machine crashes
+    /// before the operating system wrote it to disk.
+    pub fsync: bool,
+    /// Opens the store without taking the lock and rejects all writes.
+    ///
+    /// This lets tools and replicas read a store while another process
+    /// writes to it.  Buckets are reloaded when their files change, but a
+    /// cursor fetch can miss keys that are being written to other days
+    /// at the same time.
+    pub read_only: bool,
+    /// The number of days before the current day that are kept.
+    pub retention_days: u32,
+}
+
+impl Default for FileStoreOptions {
+    fn default() -> FileStoreOptions {
+        FileStoreOptions {
+            fsync: true,
+            read_only: false,
+            retention_days: DAYS_WINDOW,
+        }
+    }
+}
+
+/// The result of checking a bucket file for corruption.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketReport {
+    /// The day of the bucket.
+    pub day: u32,
+    /// The number of intact keys.
+    pub keys: usize,
+    /// The number of records with a bad checksum.
+    pub corrupted: usize,
+}
+
+impl FileKeyStore {
+    /// Opens a daily tracing key store with the default options.
+    pub fn open<P: AsRef<Path>>(p: P) -> Result<FileKeyStore, io::Error> {
+        FileKeyStore::open_with_options(p, FileStoreOptions::default())
+    }
+
+    /// Opens a daily tracing key store
+    ///
+    /// Buckets written before sequence numbers were introduced are upgraded
+    /// in place and have sequence numbers assigned.  Records torn by a crash
+    /// in the middle of a write are truncated.  Fails if the store is
+    /// already opened writable by another process.
+    pub fn open_with_options<P: AsRef<Path>>(
+        p: P,
+        options: FileStoreOptions,
+    ) -> Result<FileKeyStore, io::Error> {
+        let path = p.as_ref().to_path_buf();
+        let lock = if options.read_only {
+            None
+        } else {
+            fs::create_dir_all(&path)?;
+            Some(lock_store(&path)?)
+        };
+
+        let mut last_seq = 0;
+        let mut legacy_buckets = vec![];
+        for dir_entry in fs::read_dir(&path)? {
+            let bucket_path = dir_entry?.path();
+            let filename = bucket_path.file_name().and_then(|x| x.to_str());
+            if filename.and_then(parse_segment_filename).is_some() {
+                // the whole segment is checked so that a corrupted header
+                // cannot move the sequence numbers
+                match decode_segment(&fs::read(&bucket_path)?) {
+                    Ok((header, _)) => last_seq = last_seq.max(header.max_seq),
+                    Err(err) => {
+                        log::error!("bad segment {}: {}", bucket_path.display(), err)
+                    }
+                }
+                continue;
+            }
+            if filename.and_then(parse_bucket_filename).is_none() {
+                continue;
+            }
+            let mut f = fs::OpenOptions::new()
+                .read(true)
+                .write(!options.read_only)
+                .open(&bucket_path)?;
+            let mut header = [0u8; 8];
+            let is_legacy = match f.read_exact(&mut header) {
+                Ok(()) => &header != BUCKET_HEADER,
+                // a crash while writing the first record can leave a
+                // partial header behind
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    f.seek(SeekFrom::Start(0))?;
+                    let mut partial = vec![];
+                    f.read_to_end(&mut partial)?;
+                    partial.is_empty() || !BUCKET_HEADER.starts_with(&partial)
+                }
+                Err(err) => return Err(err),
+            };
+            if is_legacy {
+                legacy_buckets.push(bucket_path);
+                continue;
+            }
+            // a torn record is ignored by readers, only writers need to
+            // remove it before appending
+            if !options.read_only {
+                truncate_torn_record(&bucket_path, &mut f, options.fsync)?;
+            }
+            if let Some(seq) = read_last_seq(&mut f)? {
+                last_seq = last_seq.max(seq);
+            }
+        }
+
+        if options.read_only && !legacy_buckets.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "store needs to be upgraded, open it writable once",
+            ));
+        }
+        for bucket_path in legacy_buckets {
+            let (keys, corrupted) = parse_legacy_bucket(&fs::read(&bucket_path)?);
+            if corrupted > 0 {
+                log::error!(
+                    "skipped {} corrupted records in bucket {}",
+                    corrupted,
+                    bucket_path.display()
+                );
+            }
+            let entries: Vec<_> = keys
+                .into_iter()
+                .map(|key| {
+                    last_seq += 1;
+                    Entry { seq: last_seq, key }
+                })
+                .collect();
+            log::info!(
+                "upgrading bucket {} ({} keys)",
+                bucket_path.display(),
+                entries.len()
+            );
+            write_bucket(&bucket_path, &entries, options.fsync)?;
+        }
+
+        let store = FileKeyStore {
+            path,
+            options,
+            buckets: RwLock::new(BTreeMap::new()),
+            index: RwLock::new(DedupIndex::new()),
+            next_seq: AtomicU64::new(last_seq.checked_add(1).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "sequence numbers are exhausted")
+            })?),
+            pending: Mutex::new(BTreeSet::new()),
+            stamps: Mutex::new(HashMap::new()),
+            corrupt_days: Mutex::new(BTreeSet::new()),
+            _lock: lock,
+        };
+        store.rebuild_index()?;
+        Ok(store)
+    }
+
+    /// Rebuilds the dedup index from all retained buckets.
+    fn rebuild_index(&self) -> Result<(), io::Error> {
+        let mut index = DedupIndex::new();
+        for day in self.retained_days() {
+            self.with_bucket(day, |loaded| {
+                for entry in &loaded.entries {
+                    index.insert(entry.key, day);
+                }
+            })?;
+        }
+        *self.index.write().unwrap() = index;
+        Ok(())
+    }
+
+    /// Checks all bucket and segment files for corrupted records.
+    pub fn check(&self) -> Result<Vec<BucketReport>, io::Error> {
+        let mut rv = BTreeMap::new();
+        for dir_entry in fs::read_dir(&self.path)? {
+            let dir_entry = dir_entry?;
+            let filename = dir_entry.file_name();
+            let filename = filename.to_str().unwrap_or("");
+            let (day, keys, corrupted) = if let Some(day) = parse_bucket_filename(filename) {
+                let data = fs::read(dir_entry.path())?;
+                match parse_bucket(&data) {
+                    Ok((bucket, corrupted)) => (day, bucket.entries.len(), corrupted),
+                    Err(_) => (day, 0, (data.len() / RECORD_SIZE).max(1)),
+                }
+            } else if let Some(day) = parse_segment_filename(filename) {
+                let data = fs::read(dir_entry.path())?;
+                match decode_segment(&data) {
+                    Ok((_, entries)) => (day, entries.len(), 0),
+                    Err(_) => (
+                        day,
+                        0,
+                        decode_segment_header(&data).map_or(1, |header| header.count as usize),
+                    ),
+                }
+            } else {
+                continue;
+            };
+            let report = rv.entry(day).or_insert(BucketReport {
+                day,
+                keys: 0,
+                corrupted: 0,
+            });
+            report.keys += keys;
+            report.corrupted += corrupted;
+        }
+        Ok(rv.into_values().collect())
+    }
+
+    /// Rewrites the buckets of all closed days into compressed segments.
+    ///
+    /// Keys submitted late for a compacted day go into a new bucket file
+    /// and are merged into the segment the next time this runs.  Returns
+    /// the days that were compacted.
+    fn compact_closed_days(&self) -> Result<Vec<DayInfo>, io::Error> {
+        let today = self.current_day();
+        let mut days = vec![];
+        for dir_entry in fs::read_dir(&self.path)? {
+            if let Some(day) = dir_entry?
+                .file_name()
+                .to_str()
+                .and_then(parse_bucket_filename)
+            {
+                if day < today {
+                    days.push(day);
+                }
+            }
+        }
+        days.sort_unstable();
+
+        let mut rv = vec![];
+        for day in days {
+            let slot = self.slot(day);
+            let mut guard = slot.write().unwrap();
+            // never replace a segment we cannot read, it might still be
+            // recoverable by hand
+            if let Ok(data) = fs::read(self.segment_path(day)) {
+                if decode_segment(&data).is_err() {
+                    log::error!("not compacting day {}, segment is corrupted", day);
+                    continue;
+                }
+            }
+            if guard.is_none() {
+                *guard = Some(self.load_bucket(day)?);
+            }
+            let bucket = guard.as_ref().unwrap();
+            let segment = encode_segment(day, &bucket.entries)?;
+            write_atomic(&self.segment_path(day), &segment, self.options.fsync)?;
+            fs::remove_file(self.bucket_path(day))?;
+            if self.options.fsync {
+                sync_dir(&self.path)?;
+            }
+            log::info!(
+                "compacted day {} ({} keys, {} bytes)",
+                day,
+                bucket.entries.len(),
+                segment.len()
+            );
+            rv.push(DayInfo {
+                day,
+                count: bucket.entries.len(),
+            });
+        }
+        Ok(rv)
+    }
+
+    /// Fails if the store was opened read-only.
+    fn check_writable(&self) -> Result<(), io::Error> {
+        if self.options.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "store is opened read-only",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Evicts a cached bucket if its files changed on disk.
+    ///
+    /// Read-only stores call this before every access since other
+    /// processes might write to the store.
+    fn refresh(&self, day: u32) -> Result<(), io::Error> {
+        let stamp = [
+            stamp_file(&self.segment_path(day))?,
+            stamp_file(&self.bucket_path(day))?,
+        ];
+        let mut stamps = self.stamps.lock().unwrap();
+        if stamps.get(&day) != Some(&stamp) {
+            stamps.insert(day, stamp);
+            *self.slot(day).write().unwrap() = None;
+        }
+        Ok(())
+    }
+
+    fn bucket_path(&self, day: u32) -> PathBuf {
+        self.path.join(format!("_{}.bucket", day))
+    }
+
+    fn segment_path(&self, day: u32) -> PathBuf {
+        self.path.join(format!("_{}.segment", day))
+    }
+
+    /// Returns the lock of a bucket, creating it if necessary.
+    fn slot(&self, day: u32) -> BucketSlot {
+        if let Some(slot) = self.buckets.read().unwrap().get(&day) {
+            return slot.clone();
+        }
+        self.buckets
+            .write()
+            .unwrap()
+            .entry(day)
+            .or_default()
+            .clone()
+    }
+
+    /// Reads the segment of a day into a bucket.
+    fn load_segment(&self, day: u32, loaded: &mut Bucket) -> Result<(), io::Error> {
+        let path = self.segment_path(day);
+        match fs::read(&path) {
+            Ok(data) => {
+                match decode_segment(&data) {
+                    Ok((header, entries)) if header.day == day => {
+                        entries.into_iter().for_each(|entry| loaded.push(entry))
+                    }
+                    Ok(_) => {
+                        log::error!("skipped segment {} of wrong day", path.display());
+                        self.corrupt_days.lock().unwrap().insert(day);
+                    }
+                    Err(err) => {
+                        log::error!("skipped corrupted segment {}: {}", path.display(), err);
+                        self.corrupt_days.lock().unwrap().insert(day);
+                    }
+                }
+                loaded.modified = fs::metadata(&path)?.modified().ok();
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Reads the segment and bucket file of a day from disk.
+    fn load_bucket(&self, day: u32) -> Result<Bucket, io::Error> {
+        let started = Instant::now();
+        let mut loaded = Bucket::default();
+        let segment_stamp = stamp_file(&self.segment_path(day))?;
+        self.load_segment(day, &mut loaded)?;
+
+        let path = self.bucket_path(day);
+        match fs::read(&path) {
+            Ok(data) => match parse_bucket(&data) {
+                Ok((bucket, corrupted)) => {
+                    if corrupted > 0 {
+                        log::error!(
+                            "skipped {} corrupted records in bucket {}",
+                            corrupted,
+                            path.display()
+                        );
+                        self.corrupt_days.lock().unwrap().insert(day);
+                    }
+                    bucket
+                        .entries
+                        .into_iter()
+                        .for_each(|entry| loaded.push(entry));
+                    loaded.modified = loaded.modified.max(fs::metadata(&path)?.modified().ok());
+                }
+                Err(err) => {
+                    log::error!("skipped corrupted bucket {}: {}", path.display(), err);
+                    self.corrupt_days.lock().unwrap().insert(day);
+                    // new keys must not be appended behind a broken header
+                    if !self.options.read_only {
+                        let mut corrupt_path = path.as_os_str().to_owned();
+                        corrupt_path.push(".corrupt");
+                        fs::rename(&path, corrupt_path)?;
+                    }
+                }
+            },
+            // compaction writes the segment before it removes the bucket, so
+            // a bucket that disappeared went into a newer segment
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                if stamp_file(&self.segment_path(day))? != segment_stamp {
+                    loaded = Bucket::default();
+                    self.load_segment(day, &mut loaded)?;
+                }
+            }
+            Err(err) => return Err(err),
+        }
+
+        METRICS.bucket_loaded(started.elapsed());
+        Ok(loaded)
+    }
+
+    /// Calls a function with a bucket, loading it from disk if needed.
+    ///
+    /// Only the lock of the bucket itself is held while it is loaded, and
+    /// it is checked again after acquiring the write lock so that a bucket
+    /// is never read twice.
+    fn with_bucket<R, F>(&self, day: u32, f: F) -> Result<R, io::Error>
+    where
+        F: FnOnce(&Bucket) -> R,
+    {
+        if self.options.read_only {
+            self.refresh(day)?;
+        }
+        let slot = self.slot(day);
+        if let Some(ref loaded) = *slot.read().unwrap() {
+            return Ok(f(loaded));
+        }
+        let mut guard = slot.write().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.load_bucket(day)?);
+        }
+        Ok(f(guard.as_ref().unwrap()))
+    }
+
+    /// Allocates the sequence number for a write.
+    ///
+    /// Keys only become visible to cursor fetches once all writes with
+    /// lower sequence numbers finished so that a cursor never skips a key.
+    fn begin_write(&self) -> PendingWrite<'_> {
+        let mut pending = self.pending.lock().unwrap();
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        pending.insert(seq);
+        PendingWrite { store: self, seq }
+    }
+
+    /// Claims an existing sequence number for an imported key.
+    fn begin_import(&self, seq: u64) -> Result<PendingWrite<'_>, io::Error> {
+        let mut pending = self.pending.lock().unwrap();
+        check_import_seq(seq, self.next_seq.load(Ordering::SeqCst))?;
+        self.next_seq.store(seq + 1, Ordering::SeqCst);
+        pending.insert(seq);
+        Ok(PendingWrite { store: self, seq })
+    }
+
+    /// Returns the first sequence number that is not visible yet.
+    fn visible_seq(&self) -> u64 {
+        // writes of other processes cannot be tracked
+        if self.options.read_only {
+            return u64::MAX;
+        }
+        let pending = self.pending.lock().unwrap();
+        pending
+            .iter()
+            .next()
+            .copied()
+            .unwrap_or_else(|| self.next_seq.load(Ordering::SeqCst))
+    }
+
+    /// Appends an entry to a bucket file.
+    fn append_entry(&self, day: u32, entry: &Entry) -> Result<(), io::Error> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.bucket_path(day))?;
+        let mut msg = BytesMut::new();
+        let len = file.metadata()?.len();
+        let is_new = len == 0;
+        if is_new {
+            msg.put_slice(BUCKET_HEADER);
+        }
+        encode_entry(&mut msg, entry);
+        let written = file
+            .write_all(&msg)
+            .and_then(|_| file.flush())
+            .and_then(|_| {
+                if self.options.fsync {
+                    file.sync_data()?;
+                }
+                Ok(())
+            });
+        if let Err(err) = written {
+            // a torn record would make the whole bucket unreadable
+            file.set_len(len).ok();
+            return Err(err);
+        }
+        if self.options.fsync && is_new {
+            sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a key to its bucket.
+    fn write_key(&self, seq: Option<u64>, day: u32, key: DailyTracingKey) -> Result<(), io::Error> {
+        let slot = self.slot(day);
+        let mut guard = slot.write().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.load_bucket(day)?);
+        }
+        // the sequence number is allocated under the bucket lock so that
+        // records within a bucket file are always in sequence order
+        let write = match seq {
+            Some(seq) => self.begin_import(seq)?,
+            None => self.begin_write(),
+        };
+        let entry = Entry {
+            seq: write.seq,
+            key,
+        };
+        self.append_entry(day, &entry)?;
+        let bucket = guard.as_mut().unwrap();
+        bucket.push(entry);
+        bucket.modified = Some(SystemTime::now());
+        Ok(())
+    }
+
+    /// Adds a key with a new or the given sequence number.
+    fn insert_key(
+        &self,
+        seq: Option<u64>,
+        day: u32,
+        key: DailyTracingKey,
+    ) -> Result<bool, io::Error> {
+        self.check_writable()?;
+        if seq.is_none() {
+            self.check_day(day)?;
+        }
+        // reserve the key in the index first so that concurrent submissions
+        // of the same key are rejected without holding a lock across I/O
+        {
+            let mut index = self.index.write().unwrap();
+            if index.contains(&key, &self.retained_days()) {
+                return Ok(false);
+            }
+            index.insert(key, day);
+        }
+
+        if let Err(err) = self.write_key(seq, day, key) {
+            self.index.write().unwrap().remove(&key, day);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Returns all days that have a bucket or segment file.
+    fn stored_days(&self) -> Result<Vec<u32>, io::Error> {
+        let mut days = vec![];
+        for dir_entry in fs::read_dir(&self.path)? {
+            if let Some(day) = dir_entry?.file_name().to_str().and_then(parse_day_filename) {
+                days.push(day);
+            }
+        }
+        days.sort_unstable();
+        days.dedup();
+        Ok(days)
+    }
+
+    /// Removes all buckets, segments and corrupt buckets before a day with
+    /// the given function.
+    ///
+    /// This also evicts all buckets before that day from memory.
+    fn remove_days<F>(&self, before: u32, mut remove: F) -> Result<Vec<DayInfo>, io::Error>
+    where
+        F: FnMut(&Path) -> Result<(), io::Error>,
+    {
+        let mut rv = vec![];
+        for day in self.stored_days()? {
+            if day >= before {
+                break;
+            }
+            let slot = self.slot(day);
+            let mut guard = slot.write().unwrap();
+            let count = match *guard {
+                Some(ref loaded) => loaded.entries.len(),
+                None => self.load_bucket(day)?.entries.len(),
+            };
+            for path in &[self.segment_path(day), self.bucket_path(day)] {
+                if path.exists() {
+                    remove(path)?;
+                }
+            }
+            *guard = None;
+            rv.push(DayInfo { day, count });
+        }
+
+        // buckets that were set aside as corrupt expire with their day
+        for dir_entry in fs::read_dir(&self.path)? {
+            let dir_entry = dir_entry?;
+            let day = dir_entry
+                .file_name()
+                .to_str()
+                .and_then(parse_corrupt_filename);
+            if day.is_some_and(|day| day < before) {
+                remove(&dir_entry.path())?;
+            }
+        }
+
+        // the index is always locked before the buckets
+        let mut index = self.index.write().unwrap();
+        let mut buckets = self.buckets.write().unwrap();
+        *buckets = buckets.split_off(&before);
+        index.evict_before(before);
+        let mut corrupt_days = self.corrupt_days.lock().unwrap();
+        *corrupt_days = corrupt_days.split_off(&before);
+        Ok(rv)
+    }
+}
+
+/// A sequence number that was allocated but not written yet.
+struct PendingWrite<'a> {
+    store: &'a FileKeyStore,
+    seq: u64,
+}
+
+impl Drop for PendingWrite<'_> {
+    fn drop(&mut self) {
+        self.store.pending.lock().unwrap().remove(&self.seq);
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn retention_days(&self) -> u32 {
+        self.options.retention_days
+    }
+
+    fn corrupt_days(&self) -> Vec<u32> {
+        self.corrupt_days.lock().unwrap().iter().copied().collect()
+    }
+
+    fn add_key(&self, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        self.insert_key(None, day, key)
+    }
+
+    fn import_key(&self, seq: u64, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        self.insert_key(Some(seq), day, key)
+    }
+
+    fn has_key(&self, key: DailyTracingKey) -> Result<bool, io::Error> {
+        Ok(self
+            .index
+            .read()
+            .unwrap()
+            .contains(&key, &self.retained_days()))
+    }
+
+    fn fetch_range(
+        &self,
+        days: RangeInclusive<u32>,
+    ) -> Result<Vec<(u32, DailyTracingKey)>, io::Error> {
+        let mut rv = vec![];
+        for day in days {
+            self.with_bucket(day, |loaded| {
+                rv.extend(loaded.entries.iter().map(|entry| (day, entry.key)))
+            })?;
+        }
+        Ok(rv)
+    }
+
+    fn fetch_entries_since(&self, cursor: u64, limit: usize) -> Result<EntryPage, io::Error> {
+        let visible_seq = self.visible_seq();
+        let mut entries = vec![];
+        for day in self.retained_days() {
+            self.with_bucket(day, |loaded| {
+                entries.extend(
+                    loaded
+                        .entries
+                        .iter()
+                        .filter(|entry| entry.seq >= cursor && entry.seq < visible_seq)
+                        .map(|entry| (entry.seq, day, entry.key)),
+                )
+            })?;
+        }
+        Ok(make_entry_page(entries, cursor, limit))
+    }
+
+    fn seq_watermark(&self) -> Option<u64> {
+        // writes of other processes cannot be tracked
+        if self.options.read_only {
+            return None;
+        }
+        Some(self.visible_seq())
+    }
+
+    fn dump(&self) -> Result<Vec<(u64, u32, DailyTracingKey)>, io::Error> {
+        let visible_seq = self.visible_seq();
+        let mut entries = vec![];
+        for day in self.stored_days()? {
+            self.with_bucket(day, |loaded| {
+                entries.extend(
+                    loaded
+                        .entries
+                        .iter()
+                        .filter(|entry| entry.seq < visible_seq)
+                        .map(|entry| (entry.seq, day, entry.key)),
+                )
+            })?;
+        }
+        entries.sort_by_key(|&(seq, _, _)| seq);
+        Ok(entries)
+    }
+
+    fn day_summary(&self, day: u32) -> Result<Option<DaySummary>, io::Error> {
+        self.with_bucket(day, |loaded| loaded.summary())
+    }
+
+    fn purge(&self, before: u32) -> Result<Vec<DayInfo>, io::Error> {
+        self.check_writable()?;
+        self.remove_days(before, |path| fs::remove_file(path))
+    }
+
+    fn compact(&self) -> Result<Vec<DayInfo>, io::Error> {
+        self.check_writable()?;
+        self.compact_closed_days()
+    }
+
+    fn flush(&self) -> Result<(), io::Error> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        for day in self.stored_days()? {
+            for path in &[self.segment_path(day), self.bucket_path(day)] {
+                match fs::File::open(path) {
+                    Ok(f) => f.sync_all()?,
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        sync_dir(&self.path)
+    }
+
+    fn archive(&self, before: u32, archive_path: &Path) -> Result<Vec<DayInfo>, io::Error> {
+        self.check_writable()?;
+        fs::create_dir_all(archive_path)?;
+        self.remove_days(before, |path| {
+            let target = archive_path.join(path.file_name().unwrap());
+            if target.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "bucket already archived",
+                ));
+            }
+            // renames fail across file systems, fall back to copying
+            if fs::rename(path, &target).is_err() {
+                fs::copy(path, &target)?;
+                fs::remove_file(path)?;
+            }
+            Ok(())
+        })
+    }
+}
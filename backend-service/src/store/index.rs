@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use contact_tracing::DailyTracingKey;
+
+/// Maps every known key to the day it was submitted for.
+///
+/// This lets stores check for duplicates across the whole retention window
+/// with a single lookup instead of probing every bucket.
+#[derive(Debug, Default)]
+pub struct DedupIndex {
+    days: HashMap<DailyTracingKey, u32>,
+}
+
+impl DedupIndex {
+    /// Creates an empty index.
+    pub fn new() -> DedupIndex {
+        DedupIndex::default()
+    }
+
+    /// Returns the number of indexed keys.
+    pub fn len(&self) -> usize {
+        self.days.len()
+    }
+
+    /// Returns `true` if no keys are indexed.
+    pub fn is_empty(&self) -> bool {
+        self.days.is_empty()
+    }
+
+    /// Records the day of a key.
+    pub fn insert(&mut self, key: DailyTracingKey, day: u32) {
+        self.days.insert(key, day);
+    }
+
+    /// Removes a key if it is recorded for the given day.
+    pub fn remove(&mut self, key: &DailyTracingKey, day: u32) {
+        if self.days.get(key) == Some(&day) {
+            self.days.remove(key);
+        }
+    }
+
+    /// Checks if a key is known for any of the given days.
+    pub fn contains(&self, key: &DailyTracingKey, days: &RangeInclusive<u32>) -> bool {
+        self.days.get(key).is_some_and(|day| days.contains(day))
+    }
+
+    /// Removes all keys of days before the given day.
+    pub fn evict_before(&mut self, before: u32) {
+        self.days.retain(|_, day| *day >= before);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use chrono::Utc;
+use contact_tracing::{day_number_for_timestamp, DailyTracingKey};
+
+use crate::store::{
+    check_import_seq, make_entry_page, write_archive, Bucket, DayInfo, DaySummary, DedupIndex,
+    Entry, EntryPage, KeyStore, DAYS_WINDOW,
+};
+
+/// Keeps daily tracing keys in memory.
+///
+/// Nothing is persisted so this is mostly useful for tests.
+pub struct MemoryKeyStore {
+    buckets: RwLock<BTreeMap<u32, Bucket>>,
+    index: RwLock<DedupIndex>,
+    next_seq: AtomicU64,
+    current_day: Option<u32>,
+    retention_days: u32,
+}
+
+impl fmt::Debug for MemoryKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryKeyStore").finish()
+    }
+}
+
+impl Default for MemoryKeyStore {
+    fn default() -> MemoryKeyStore {
+        MemoryKeyStore::new()
+    }
+}
+
+impl MemoryKeyStore {
+    /// Creates an empty store.
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore {
+            buckets: RwLock::new(BTreeMap::new()),
+            index: RwLock::new(DedupIndex::new()),
+            next_seq: AtomicU64::new(1),
+            current_day: None,
+            retention_days: DAYS_WINDOW,
+        }
+    }
+
+    /// Creates an empty store that treats the given day as the current day.
+    pub fn with_current_day(day: u32) -> MemoryKeyStore {
+        MemoryKeyStore {
+            current_day: Some(day),
+            ..MemoryKeyStore::new()
+        }
+    }
+
+    /// Keeps the given number of days before the current day.
+    pub fn with_retention_days(mut self, days: u32) -> MemoryKeyStore {
+        self.retention_days = days;
+        self
+    }
+
+    /// Removes all days before the given day after passing them to a
+    /// function.
+    ///
+    /// The store is locked throughout so no key can slip in between.
+    fn remove_days<F>(&self, before: u32, f: F) -> Result<Vec<DayInfo>, io::Error>
+    where
+        F: FnOnce(&BTreeMap<u32, Bucket>) -> Result<(), io::Error>,
+    {
+        // the index is always locked before the buckets
+        let mut index = self.index.write().unwrap();
+        let mut buckets = self.buckets.write().unwrap();
+        let kept = buckets.split_off(&before);
+        if let Err(err) = f(&buckets) {
+            buckets.extend(kept);
+            return Err(err);
+        }
+        let removed = std::mem::replace(&mut *buckets, kept);
+        index.evict_before(before);
+        Ok(removed
+            .into_iter()
+            .map(|(day, bucket)| DayInfo {
+                day,
+                count: bucket.entries.len(),
+            })
+            .collect())
+    }
+
+    /// Adds a key with a new or the given sequence number.
+    fn insert_key(
+        &self,
+        seq: Option<u64>,
+        day: u32,
+        key: DailyTracingKey,
+    ) -> Result<bool, io::Error> {
+        if seq.is_none() {
+            self.check_day(day)?;
+        }
+        let mut index = self.index.write().unwrap();
+        if index.contains(&key, &self.retained_days()) {
+            return Ok(false);
+        }
+
+        let seq = match seq {
+            Some(seq) => {
+                check_import_seq(seq, self.next_seq.load(Ordering::SeqCst))?;
+                self.next_seq.store(seq + 1, Ordering::SeqCst);
+                seq
+            }
+            None => self.next_seq.fetch_add(1, Ordering::SeqCst),
+        };
+
+        let mut buckets = self.buckets.write().unwrap();
+        let bucket = buckets.entry(day).or_default();
+        bucket.push(Entry { seq, key });
+        bucket.modified = Some(SystemTime::now());
+        index.insert(key, day);
+        Ok(true)
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn current_day(&self) -> u32 {
+        self.current_day
+            .unwrap_or_else(|| day_number_for_timestamp(&Utc::now()))
+    }
+
+    fn retention_days(&self) -> u32 {
+        self.retention_days
+    }
+
+    fn add_key(&self, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        self.insert_key(None, day, key)
+    }
+
+    fn import_key(&self, seq: u64, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        self.insert_key(Some(seq), day, key)
+    }
+
+    fn has_key(&self, key: DailyTracingKey) -> Result<bool, io::Error> {
+        Ok(self
+            .index
+            .read()
+            .unwrap()
+            .contains(&key, &self.retained_days()))
+    }
+
+    fn fetch_range(
+        &self,
+        days: RangeInclusive<u32>,
+    ) -> Result<Vec<(u32, DailyTracingKey)>, io::Error> {
+        if days.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .buckets
+            .read()
+            .unwrap()
+            .range(days)
+            .flat_map(|(&day, bucket)| bucket.entries.iter().map(move |entry| (day, entry.key)))
+            .collect())
+    }
+
+    fn fetch_entries_since(&self, cursor: u64, limit: usize) -> Result<EntryPage, io::Error> {
+        let entries = self
+            .buckets
+            .read()
+            .unwrap()
+            .range(self.retained_days())
+            .flat_map(|(&day, bucket)| {
+                bucket
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.seq >= cursor)
+                    .map(move |entry| (entry.seq, day, entry.key))
+            })
+            .collect();
+        Ok(make_entry_page(entries, cursor, limit))
+    }
+
+    fn seq_watermark(&self) -> Option<u64> {
+        // keys are added to their bucket while the index is locked
+        let _index = self.index.read().unwrap();
+        Some(self.next_seq.load(Ordering::SeqCst))
+    }
+
+    fn dump(&self) -> Result<Vec<(u64, u32, DailyTracingKey)>, io::Error> {
+        let mut entries: Vec<_> = self
+            .buckets
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|(&day, bucket)| {
+                bucket
+                    .entries
+                    .iter()
+                    .map(move |entry| (entry.seq, day, entry.key))
+            })
+            .collect();
+        entries.sort_by_key(|&(seq, _, _)| seq);
+        Ok(entries)
+    }
+
+    fn day_summary(&self, day: u32) -> Result<Option<DaySummary>, io::Error> {
+        Ok(self
+            .buckets
+            .read()
+            .unwrap()
+            .get(&day)
+            .and_then(|bucket| bucket.summary()))
+    }
+
+    fn purge(&self, before: u32) -> Result<Vec<DayInfo>, io::Error> {
+        self.remove_days(before, |_| Ok(()))
+    }
+
+    fn archive(&self, before: u32, archive_path: &Path) -> Result<Vec<DayInfo>, io::Error> {
+        let current_day = self.current_day();
+        self.remove_days(before, |removed| {
+            let keys: Vec<_> = removed
+                .iter()
+                .flat_map(|(&day, bucket)| bucket.entries.iter().map(move |entry| (day, entry.key)))
+                .collect();
+            write_archive(archive_path, &keys, current_day)
+        })
+    }
+}
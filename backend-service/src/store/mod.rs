@@ -0,0 +1,491 @@
+//! Storage of daily tracing keys.
+//!
+//! The server is generic over the [`KeyStore`] trait and accesses it
+//! through an [`AsyncKeyStore`].  The following backends are provided:
+//!
+//! * [`FileKeyStore`]: stores one append only bucket file per day
+//! * [`MemoryKeyStore`]: keeps everything in memory, useful for tests
+//! * [`SqliteKeyStore`]: stores keys in an SQLite database
+//!
+//! Keys submitted for a region are additionally kept in a store per region
+//! which is managed by [`Partitions`].
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use contact_tracing::{day_number_for_timestamp, DailyTracingKey};
+
+mod async_store;
+mod file;
+mod index;
+mod memory;
+mod partition;
+mod segment;
+mod sqlite;
+
+pub use self::async_store::AsyncKeyStore;
+pub use self::file::{BucketReport, FileKeyStore, FileStoreOptions};
+pub use self::index::DedupIndex;
+pub use self::memory::MemoryKeyStore;
+pub use self::partition::{Partitions, Region};
+pub use self::sqlite::SqliteKeyStore;
+
+/// The number of days keys are retained.
+pub const DAYS_WINDOW: u32 = 21;
+
+/// A key in a bucket together with its sequence number.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Entry {
+    pub seq: u64,
+    pub key: DailyTracingKey,
+}
+
+/// An in-memory copy of the keys of a day.
+#[derive(Default)]
+pub(crate) struct Bucket {
+    pub entries: Vec<Entry>,
+    pub keys: HashSet<DailyTracingKey>,
+    pub digest: Sha256,
+    pub modified: Option<SystemTime>,
+}
+
+impl Bucket {
+    pub fn push(&mut self, entry: Entry) {
+        if self.keys.insert(entry.key) {
+            self.digest.input(entry.key.as_bytes());
+            self.entries.push(entry);
+        }
+    }
+
+    pub fn summary(&self) -> Option<DaySummary> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        Some(DaySummary {
+            count: self.entries.len(),
+            digest: self.digest.clone().result().to_vec(),
+            modified: self.modified,
+        })
+    }
+}
+
+/// Fails an import whose sequence number was already used.
+pub(crate) fn check_import_seq(seq: u64, next_seq: u64) -> Result<(), io::Error> {
+    if seq < next_seq {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sequence number was already used",
+        ));
+    }
+    Ok(())
+}
+
+/// Adds keys to the archive at the given path.
+pub(crate) fn write_archive(
+    archive_path: &Path,
+    keys: &[(u32, DailyTracingKey)],
+    current_day: u32,
+) -> Result<(), io::Error> {
+    let first_day = match keys.iter().map(|&(day, _)| day).min() {
+        Some(first_day) => first_day,
+        None => return Ok(()),
+    };
+    // the archive keeps all days that are archived into it
+    let archive = FileKeyStore::open_with_options(
+        archive_path,
+        FileStoreOptions {
+            retention_days: current_day.saturating_sub(first_day),
+            ..Default::default()
+        },
+    )?;
+    for &(day, key) in keys {
+        archive.add_key(day, key)?;
+    }
+    Ok(())
+}
+
+/// Builds an entry page out of `(seq, day, key)` tuples.
+pub(crate) fn make_entry_page(
+    mut entries: Vec<(u64, u32, DailyTracingKey)>,
+    cursor: u64,
+    limit: usize,
+) -> EntryPage {
+    entries.sort_by_key(|&(seq, _, _)| seq);
+    let has_more = entries.len() > limit;
+    entries.truncate(limit);
+    EntryPage {
+        next_cursor: entries.last().map_or(cursor, |&(seq, _, _)| seq + 1),
+        entries,
+        has_more,
+    }
+}
+
+/// Cache validation information for a range of buckets.
+#[derive(Debug, Clone)]
+pub struct CacheInfo {
+    /// A digest over the contents of all buckets in the range.
+    pub digest: String,
+    /// When the most recent bucket in the range was last written to.
+    pub last_modified: Option<SystemTime>,
+    /// Indicates that the range only covers days that already ended.
+    pub closed: bool,
+}
+
+/// Summarizes the contents of a single day.
+#[derive(Debug, Clone)]
+pub struct DaySummary {
+    /// The number of keys stored for the day.
+    pub count: usize,
+    /// A SHA-256 digest over the keys in insertion order.
+    pub digest: Vec<u8>,
+    /// When the day was last written to.
+    pub modified: Option<SystemTime>,
+}
+
+/// The number of keys stored for a day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DayInfo {
+    pub day: u32,
+    pub count: usize,
+}
+
+/// The keys of a single day.
+#[derive(Serialize, Debug)]
+pub struct DayKeys {
+    pub day: u32,
+    pub keys: Vec<DailyTracingKey>,
+}
+
+/// A page of keys returned by a cursor based fetch.
+#[derive(Serialize, Debug)]
+pub struct KeyPage {
+    /// The keys with their day numbers in sequence order.
+    pub keys: Vec<(u32, DailyTracingKey)>,
+    /// The cursor to pass to fetch the next page.
+    pub next_cursor: u64,
+    /// Indicates that more keys are available after this page.
+    pub has_more: bool,
+}
+
+/// A page of keys with their sequence numbers.
+#[derive(Debug)]
+pub struct EntryPage {
+    /// The keys as `(seq, day, key)` in sequence order.
+    pub entries: Vec<(u64, u32, DailyTracingKey)>,
+    /// The cursor to pass to fetch the next page.
+    pub next_cursor: u64,
+    /// Indicates that more keys are available after this page.
+    pub has_more: bool,
+}
+
+impl From<EntryPage> for KeyPage {
+    fn from(page: EntryPage) -> KeyPage {
+        KeyPage {
+            keys: page
+                .entries
+                .into_iter()
+                .map(|(_, day, key)| (day, key))
+                .collect(),
+            next_cursor: page.next_cursor,
+            has_more: page.has_more,
+        }
+    }
+}
+
+/// A storage backend for daily tracing keys.
+pub trait KeyStore: fmt::Debug + Send + Sync + 'static {
+    /// Adds a tracing key for a day.
+    ///
+    /// Returns `false` if the key was already known.  Days outside of the
+    /// retention window are rejected with `InvalidInput`.
+    fn add_key(&self, day: u32, key: DailyTracingKey) -> Result<bool, io::Error>;
+
+    /// Checks if a tracing key is already known.
+    fn has_key(&self, key: DailyTracingKey) -> Result<bool, io::Error>;
+
+    /// Returns the keys of a range of days with their day numbers.
+    fn fetch_range(
+        &self,
+        days: RangeInclusive<u32>,
+    ) -> Result<Vec<(u32, DailyTracingKey)>, io::Error>;
+
+    /// Returns up to `limit` keys with their sequence numbers starting at
+    /// the given cursor.
+    ///
+    /// The cursor is the sequence number of the first key to return.  A
+    /// cursor of `0` starts from the beginning of the retention window.
+    fn fetch_entries_since(&self, cursor: u64, limit: usize) -> Result<EntryPage, io::Error>;
+
+    /// Returns the first sequence number that is not visible to cursor
+    /// fetches yet.
+    ///
+    /// This only changes when new keys become visible, which lets callers
+    /// skip fetches when nothing was written.  Stores that cannot tell
+    /// cheaply return `None`.
+    fn seq_watermark(&self) -> Option<u64> {
+        None
+    }
+
+    /// Summarizes a day or returns `None` if no keys are stored for it.
+    fn day_summary(&self, day: u32) -> Result<Option<DaySummary>, io::Error>;
+
+    /// Returns all stored keys as `(seq, day, key)` in sequence order.
+    ///
+    /// Unlike [`fetch_since`](KeyStore::fetch_since) this is not limited to
+    /// the retention window and returns a consistent view of the store.
+    fn dump(&self) -> Result<Vec<(u64, u32, DailyTracingKey)>, io::Error>;
+
+    /// Adds a tracing key with an existing sequence number.
+    ///
+    /// This is used to restore snapshots.  The sequence number must be
+    /// higher than that of any key the store ever held.  Returns `false` if
+    /// the key was already known.  Unlike [`add_key`](KeyStore::add_key)
+    /// this accepts days outside of the retention window.
+    fn import_key(&self, seq: u64, day: u32, key: DailyTracingKey) -> Result<bool, io::Error>;
+
+    /// Removes all days before the given day.
+    ///
+    /// Returns the days that were removed.
+    fn purge(&self, before: u32) -> Result<Vec<DayInfo>, io::Error>;
+
+    /// Compacts the storage of days that already ended.
+    ///
+    /// Returns the days that were compacted.  Backends without a compacted
+    /// representation do nothing.
+    fn compact(&self) -> Result<Vec<DayInfo>, io::Error> {
+        Ok(vec![])
+    }
+
+    /// Moves all days before the given day into an archive.
+    ///
+    /// The archive is a folder in the format of the [`FileKeyStore`].  Keys
+    /// written to these days while they are archived are either archived
+    /// or kept.  Returns the days that were archived.
+    fn archive(&self, before: u32, archive_path: &Path) -> Result<Vec<DayInfo>, io::Error>;
+
+    /// Returns the current day.
+    fn current_day(&self) -> u32 {
+        day_number_for_timestamp(&Utc::now())
+    }
+
+    /// Makes sure all written keys are on disk.
+    ///
+    /// Backends that persist every write do nothing.
+    fn flush(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Returns up to `limit` keys starting at the given cursor.
+    ///
+    /// See [`fetch_entries_since`](KeyStore::fetch_entries_since).
+    fn fetch_since(&self, cursor: u64, limit: usize) -> Result<KeyPage, io::Error> {
+        self.fetch_entries_since(cursor, limit).map(KeyPage::from)
+    }
+
+    /// Returns the number of days before the current day that are kept.
+    fn retention_days(&self) -> u32 {
+        DAYS_WINDOW
+    }
+
+    /// Returns the days whose files had corrupted data that was skipped.
+    ///
+    /// Days are forgotten when they are purged.
+    fn corrupt_days(&self) -> Vec<u32> {
+        vec![]
+    }
+
+    /// Returns the range of days that are currently retained.
+    fn retained_days(&self) -> RangeInclusive<u32> {
+        let bucket_end = self.current_day();
+        bucket_end.saturating_sub(self.retention_days())..=bucket_end
+    }
+
+    /// Checks that keys can be added for a day.
+    ///
+    /// Keys for days outside of the retention window would never be
+    /// deduplicated or, for future days, removed by retention.
+    fn check_day(&self, day: u32) -> Result<(), io::Error> {
+        if !self.retained_days().contains(&day) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "day is outside of the retention window",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the range of days to fetch for a timestamp.
+    ///
+    /// The range is empty if the timestamp is in the future.
+    fn day_range(&self, timestamp: DateTime<Utc>) -> Result<RangeInclusive<u32>, io::Error> {
+        let bucket_start = day_number_for_timestamp(&timestamp);
+        let bucket_end = self.current_day();
+
+        match bucket_end.checked_sub(bucket_start) {
+            None => Ok(RangeInclusive::new(1, 0)),
+            Some(diff) if diff > self.retention_days() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reading too far into the past",
+            )),
+            _ => Ok(bucket_start..=bucket_end),
+        }
+    }
+
+    /// Returns all keys after a certain timestamp with their day numbers.
+    fn fetch_keys(
+        &self,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Vec<(u32, DailyTracingKey)>, io::Error> {
+        self.fetch_range(self.day_range(timestamp)?)
+    }
+
+    /// Returns the cache validation info for a range of days.
+    ///
+    /// This needs to be called before the keys are fetched so that a
+    /// concurrent write can never be hidden behind an outdated digest.
+    fn cache_info(&self, days: RangeInclusive<u32>) -> Result<CacheInfo, io::Error> {
+        let mut digest = Sha256::new();
+        let mut last_modified = None;
+        let closed = *days.end() < self.current_day();
+        for day in days {
+            if let Some(summary) = self.day_summary(day)? {
+                digest.input(day.to_le_bytes());
+                digest.input(&summary.digest);
+                last_modified = last_modified.max(summary.modified);
+            }
+        }
+        let digest = digest.result();
+        Ok(CacheInfo {
+            digest: digest[..16].iter().map(|b| format!("{:02x}", b)).collect(),
+            last_modified,
+            closed,
+        })
+    }
+
+    /// Lists all retained days that have keys.
+    fn list_days(&self) -> Result<Vec<DayInfo>, io::Error> {
+        let mut rv = vec![];
+        for day in self.retained_days() {
+            if let Some(summary) = self.day_summary(day)? {
+                rv.push(DayInfo {
+                    day,
+                    count: summary.count,
+                });
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Returns the keys of a single day.
+    ///
+    /// Returns `None` if the day is outside of the retention window.
+    fn fetch_day(&self, day: u32) -> Result<Option<DayKeys>, io::Error> {
+        if !self.retained_days().contains(&day) {
+            return Ok(None);
+        }
+        let keys = self
+            .fetch_range(day..=day)?
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect();
+        Ok(Some(DayKeys { day, keys }))
+    }
+}
+
+/// Returns the size of all files below a folder in bytes.
+///
+/// A missing folder has no size.
+pub fn disk_usage(path: &Path) -> Result<u64, io::Error> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += disk_usage(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Forwards to a store picked at runtime, see [`Config::open_store`].
+///
+/// [`Config::open_store`]: crate::config::Config::open_store
+impl KeyStore for Box<dyn KeyStore> {
+    fn add_key(&self, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        (**self).add_key(day, key)
+    }
+
+    fn has_key(&self, key: DailyTracingKey) -> Result<bool, io::Error> {
+        (**self).has_key(key)
+    }
+
+    fn fetch_range(
+        &self,
+        days: RangeInclusive<u32>,
+    ) -> Result<Vec<(u32, DailyTracingKey)>, io::Error> {
+        (**self).fetch_range(days)
+    }
+
+    fn fetch_entries_since(&self, cursor: u64, limit: usize) -> Result<EntryPage, io::Error> {
+        (**self).fetch_entries_since(cursor, limit)
+    }
+
+    fn seq_watermark(&self) -> Option<u64> {
+        (**self).seq_watermark()
+    }
+
+    fn day_summary(&self, day: u32) -> Result<Option<DaySummary>, io::Error> {
+        (**self).day_summary(day)
+    }
+
+    fn dump(&self) -> Result<Vec<(u64, u32, DailyTracingKey)>, io::Error> {
+        (**self).dump()
+    }
+
+    fn import_key(&self, seq: u64, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        (**self).import_key(seq, day, key)
+    }
+
+    fn purge(&self, before: u32) -> Result<Vec<DayInfo>, io::Error> {
+        (**self).purge(before)
+    }
+
+    fn compact(&self) -> Result<Vec<DayInfo>, io::Error> {
+        (**self).compact()
+    }
+
+    fn archive(&self, before: u32, archive_path: &Path) -> Result<Vec<DayInfo>, io::Error> {
+        (**self).archive(before, archive_path)
+    }
+
+    fn current_day(&self) -> u32 {
+        (**self).current_day()
+    }
+
+    fn retention_days(&self) -> u32 {
+        (**self).retention_days()
+    }
+
+    fn corrupt_days(&self) -> Vec<u32> {
+        (**self).corrupt_days()
+    }
+
+    fn flush(&self) -> Result<(), io::Error> {
+        (**self).flush()
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::store::{AsyncKeyStore, KeyStore, MemoryKeyStore};
+
+/// A region keys can be partitioned by.
+///
+/// Regions are upper case two letter country codes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Region(String);
+
+impl Region {
+    /// Returns the region as string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Region {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Region, io::Error> {
+        if s.len() == 2 && s.bytes().all(|b| b.is_ascii_uppercase()) {
+            Ok(Region(s.into()))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid region {:?}", s),
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for Region {
+    type Error = io::Error;
+
+    fn try_from(s: String) -> Result<Region, io::Error> {
+        s.parse()
+    }
+}
+
+impl From<Region> for String {
+    fn from(region: Region) -> String {
+        region.0
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+type OpenPartition = dyn Fn(&Region) -> Result<Box<dyn KeyStore>, io::Error> + Send + Sync;
+
+/// Keeps a separate store for the keys of every region.
+///
+/// The partitions only hold the keys that were submitted with a region,
+/// the global store still holds all keys.  Partitions are opened on first
+/// use, regions that never had keys read as empty.
+pub struct Partitions {
+    open: Box<OpenPartition>,
+    stores: RwLock<BTreeMap<Region, AsyncKeyStore<Box<dyn KeyStore>>>>,
+    empty: AsyncKeyStore<Box<dyn KeyStore>>,
+}
+
+impl fmt::Debug for Partitions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Partitions")
+            .field("regions", &self.regions())
+            .finish()
+    }
+}
+
+impl Partitions {
+    /// Creates partitions that are opened with the given function.
+    pub fn new<F>(open: F) -> Partitions
+    where
+        F: Fn(&Region) -> Result<Box<dyn KeyStore>, io::Error> + Send + Sync + 'static,
+    {
+        Partitions {
+            open: Box::new(open),
+            stores: RwLock::new(BTreeMap::new()),
+            empty: AsyncKeyStore::new(Box::new(MemoryKeyStore::new())),
+        }
+    }
+
+    /// Opens the partition of a region, creating it if needed.
+    pub fn open(&self, region: &Region) -> Result<AsyncKeyStore<Box<dyn KeyStore>>, io::Error> {
+        if let Some(store) = self.stores.read().unwrap().get(region) {
+            return Ok(store.clone());
+        }
+        let mut stores = self.stores.write().unwrap();
+        if let Some(store) = stores.get(region) {
+            return Ok(store.clone());
+        }
+        let store = AsyncKeyStore::new((self.open)(region)?);
+        stores.insert(region.clone(), store.clone());
+        Ok(store)
+    }
+
+    /// Returns the partition of a region for reading.
+    ///
+    /// Regions without a partition are served from an empty store.
+    pub fn get(&self, region: &Region) -> AsyncKeyStore<Box<dyn KeyStore>> {
+        self.stores
+            .read()
+            .unwrap()
+            .get(region)
+            .unwrap_or(&self.empty)
+            .clone()
+    }
+
+    /// Returns all regions that have a partition.
+    pub fn regions(&self) -> Vec<Region> {
+        self.stores.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns all open partitions.
+    pub fn all(&self) -> Vec<(Region, AsyncKeyStore<Box<dyn KeyStore>>)> {
+        self.stores
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(region, store)| (region.clone(), store.clone()))
+            .collect()
+    }
+}
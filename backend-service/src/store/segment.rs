@@ -0,0 +1,160 @@
+//! Compacted segment files.
+//!
+//! Once a day is closed its append only bucket file is rewritten into a
+//! segment.  A segment starts with a fixed header followed by a deflate
+//! compressed payload:
+//!
+//! ```text
+//! magic     6 bytes  "DTKSEG"
+//! version   u16
+//! day       u32
+//! count     u32      number of keys
+//! max_seq   u64      highest sequence number in the segment
+//! checksum  u32      crc32 of the header up to here and the uncompressed payload
+//! payload            sequence number deltas (u64 each), then all keys
+//! ```
+//!
+//! All integers are little endian.  The checksum of version 1 segments only
+//! covers the payload.  Entries are deduplicated and sorted by
+//! sequence number so that cursors and cache digests are not affected by
+//! compaction.  Storing the deltas separately from the keys lets them
+//! compress to almost nothing.
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+
+use bytes::{Buf, BufMut, BytesMut};
+use crc::crc32::{self, Hasher32};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use contact_tracing::DailyTracingKey;
+
+use crate::store::Entry;
+
+/// Segment files start with this magic.
+const SEGMENT_MAGIC: &[u8; 6] = b"DTKSEG";
+
+/// The current version of the segment format.
+const SEGMENT_VERSION: u16 = 2;
+
+/// The size of the segment header.
+pub const SEGMENT_HEADER_SIZE: usize = 28;
+
+/// The size of the header without the checksum.
+const CHECKED_HEADER_SIZE: usize = SEGMENT_HEADER_SIZE - 4;
+
+/// The size of an entry in the uncompressed payload.
+const ENTRY_SIZE: usize = 24;
+
+/// The highest compression ratio deflate can reach.
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+/// The header of a segment file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentHeader {
+    pub version: u16,
+    pub day: u32,
+    pub count: u32,
+    pub max_seq: u64,
+    pub checksum: u32,
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Encodes the entries of a day into a segment.
+pub fn encode_segment(day: u32, entries: &[Entry]) -> Result<Vec<u8>, io::Error> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|entry| entry.seq);
+    let mut seen = HashSet::new();
+    entries.retain(|entry| seen.insert(entry.key));
+
+    let mut payload = BytesMut::with_capacity(entries.len() * ENTRY_SIZE);
+    let mut last_seq = 0;
+    for entry in &entries {
+        payload.put_u64_le(entry.seq - last_seq);
+        last_seq = entry.seq;
+    }
+    for entry in &entries {
+        payload.put_slice(entry.key.as_bytes());
+    }
+
+    let mut buf = BytesMut::with_capacity(SEGMENT_HEADER_SIZE);
+    buf.put_slice(SEGMENT_MAGIC);
+    buf.put_u16_le(SEGMENT_VERSION);
+    buf.put_u32_le(day);
+    buf.put_u32_le(entries.len() as u32);
+    buf.put_u64_le(last_seq);
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(&buf);
+    digest.write(&payload);
+    buf.put_u32_le(digest.sum32());
+
+    let mut encoder = DeflateEncoder::new(buf.to_vec(), Compression::best());
+    encoder.write_all(&payload)?;
+    encoder.finish()
+}
+
+/// Decodes the header of a segment.
+pub fn decode_segment_header(mut data: &[u8]) -> Result<SegmentHeader, io::Error> {
+    if data.len() < SEGMENT_HEADER_SIZE || !data.starts_with(SEGMENT_MAGIC) {
+        return Err(invalid_data("not a segment file"));
+    }
+    data.advance(SEGMENT_MAGIC.len());
+    let header = SegmentHeader {
+        version: data.get_u16_le(),
+        day: data.get_u32_le(),
+        count: data.get_u32_le(),
+        max_seq: data.get_u64_le(),
+        checksum: data.get_u32_le(),
+    };
+    if header.version == 0 || header.version > SEGMENT_VERSION {
+        return Err(invalid_data("unsupported segment version"));
+    }
+    Ok(header)
+}
+
+/// Decodes a segment into its header and entries.
+pub fn decode_segment(data: &[u8]) -> Result<(SegmentHeader, Vec<Entry>), io::Error> {
+    let header = decode_segment_header(data)?;
+    let count = header.count as usize;
+    let compressed = &data[SEGMENT_HEADER_SIZE..];
+    // the count is not trusted before the checksum was verified
+    if count * ENTRY_SIZE > compressed.len() * MAX_DEFLATE_RATIO {
+        return Err(invalid_data("bad key count, corrupted segment"));
+    }
+    let mut payload = Vec::with_capacity(count * ENTRY_SIZE);
+    DeflateDecoder::new(compressed)
+        .take((count * ENTRY_SIZE) as u64 + 1)
+        .read_to_end(&mut payload)?;
+    let checksum = if header.version == 1 {
+        crc32::checksum_ieee(&payload)
+    } else {
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        digest.write(&data[..CHECKED_HEADER_SIZE]);
+        digest.write(&payload);
+        digest.sum32()
+    };
+    if payload.len() != count * ENTRY_SIZE || checksum != header.checksum {
+        return Err(invalid_data("bad checksum, corrupted segment"));
+    }
+
+    let (mut deltas, keys) = payload.split_at(count * 8);
+    let mut seq = 0;
+    let entries = keys
+        .chunks_exact(16)
+        .map(|key| {
+            seq += deltas.get_u64_le();
+            Entry {
+                seq,
+                key: DailyTracingKey::from_bytes(key).unwrap(),
+            }
+        })
+        .collect::<Vec<_>>();
+    if entries.last().map_or(0, |entry| entry.seq) != header.max_seq {
+        return Err(invalid_data("bad max sequence number, corrupted segment"));
+    }
+    Ok((header, entries))
+}
@@ -0,0 +1,318 @@
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use contact_tracing::DailyTracingKey;
+
+use crate::store::{
+    check_import_seq, make_entry_page, write_archive, DayInfo, DaySummary, EntryPage, KeyStore,
+    DAYS_WINDOW,
+};
+
+const SCHEMA: &str = "
+    create table if not exists keys (
+        seq integer primary key autoincrement,
+        day integer not null,
+        key blob not null,
+        created integer not null
+    );
+    create index if not exists keys_day on keys (day);
+    create index if not exists keys_key on keys (key);
+";
+
+fn sql_error(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+fn read_key(data: Vec<u8>) -> Result<DailyTracingKey, rusqlite::Error> {
+    DailyTracingKey::from_bytes(&data).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Blob, Box::new(err))
+    })
+}
+
+/// Stores daily tracing keys in an SQLite database.
+///
+/// Keys live in a single `keys` table with their sequence number, day and
+/// the time they were submitted at in milliseconds since the epoch.
+pub struct SqliteKeyStore {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+    read_only: bool,
+    retention_days: u32,
+}
+
+impl fmt::Debug for SqliteKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SqliteKeyStore")
+            .field("path", &self.path)
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+impl SqliteKeyStore {
+    /// Opens or creates an SQLite database.
+    pub fn open<P: AsRef<Path>>(p: P) -> Result<SqliteKeyStore, io::Error> {
+        let path = p.as_ref().to_path_buf();
+        let conn = Connection::open(&path).map_err(sql_error)?;
+        conn.execute_batch(SCHEMA).map_err(sql_error)?;
+        Ok(SqliteKeyStore {
+            path,
+            conn: Mutex::new(conn),
+            read_only: false,
+            retention_days: DAYS_WINDOW,
+        })
+    }
+
+    /// Opens an existing SQLite database read-only.
+    ///
+    /// All writes are rejected.  SQLite takes care of reading consistently
+    /// while another process writes to the database.
+    pub fn open_read_only<P: AsRef<Path>>(p: P) -> Result<SqliteKeyStore, io::Error> {
+        let path = p.as_ref().to_path_buf();
+        let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(sql_error)?;
+        Ok(SqliteKeyStore {
+            path,
+            conn: Mutex::new(conn),
+            read_only: true,
+            retention_days: DAYS_WINDOW,
+        })
+    }
+
+    /// Keeps the given number of days before the current day.
+    pub fn with_retention_days(mut self, days: u32) -> SqliteKeyStore {
+        self.retention_days = days;
+        self
+    }
+
+    /// Fails if the database was opened read-only.
+    fn check_writable(&self) -> Result<(), io::Error> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "store is opened read-only",
+            ));
+        }
+        Ok(())
+    }
+
+    fn has_key_in(&self, conn: &Connection, key: &DailyTracingKey) -> Result<bool, io::Error> {
+        let retained = self.retained_days();
+        conn.query_row(
+            "select 1 from keys where key = ?1 and day between ?2 and ?3 limit 1",
+            params![key.as_bytes(), retained.start(), retained.end()],
+            |_| Ok(()),
+        )
+        .optional()
+        .map(|row| row.is_some())
+        .map_err(sql_error)
+    }
+
+    /// Removes all days before the given day, optionally archiving them.
+    ///
+    /// Both happen in one transaction so no key can slip in between.
+    fn remove_days(
+        &self,
+        before: u32,
+        archive_path: Option<&Path>,
+    ) -> Result<Vec<DayInfo>, io::Error> {
+        self.check_writable()?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        if let Some(archive_path) = archive_path {
+            let keys = {
+                let mut stmt = tx
+                    .prepare("select day, key from keys where day < ?1 order by day, seq")
+                    .map_err(sql_error)?;
+                let rows = stmt
+                    .query_map(params![before], |row| {
+                        Ok((row.get(0)?, read_key(row.get(1)?)?))
+                    })
+                    .map_err(sql_error)?;
+                rows.collect::<Result<Vec<_>, _>>().map_err(sql_error)?
+            };
+            write_archive(archive_path, &keys, self.current_day())?;
+        }
+        let removed = {
+            let mut stmt = tx
+                .prepare("select day, count(*) from keys where day < ?1 group by day order by day")
+                .map_err(sql_error)?;
+            let rows = stmt
+                .query_map(params![before], |row| {
+                    Ok(DayInfo {
+                        day: row.get(0)?,
+                        count: row.get::<_, i64>(1)? as usize,
+                    })
+                })
+                .map_err(sql_error)?;
+            rows.collect::<Result<Vec<_>, _>>().map_err(sql_error)?
+        };
+        tx.execute("delete from keys where day < ?1", params![before])
+            .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)?;
+        Ok(removed)
+    }
+
+    /// Adds a key with a new or the given sequence number.
+    fn insert_key(
+        &self,
+        seq: Option<u64>,
+        day: u32,
+        key: DailyTracingKey,
+    ) -> Result<bool, io::Error> {
+        self.check_writable()?;
+        if seq.is_none() {
+            self.check_day(day)?;
+        }
+        let conn = self.conn.lock().unwrap();
+        if self.has_key_in(&conn, &key)? {
+            return Ok(false);
+        }
+        if let Some(seq) = seq {
+            // autoincrement never reuses a sequence number, even after the
+            // key that had it was purged
+            let last_seq: Option<i64> = conn
+                .query_row(
+                    "select seq from sqlite_sequence where name = 'keys'",
+                    params![],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(sql_error)?;
+            check_import_seq(seq, last_seq.unwrap_or(0) as u64 + 1)?;
+        }
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        conn.execute(
+            "insert into keys (seq, day, key, created) values (?1, ?2, ?3, ?4)",
+            params![seq.map(|seq| seq as i64), day, key.as_bytes(), created],
+        )
+        .map_err(sql_error)?;
+        Ok(true)
+    }
+}
+
+impl KeyStore for SqliteKeyStore {
+    fn retention_days(&self) -> u32 {
+        self.retention_days
+    }
+
+    fn add_key(&self, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        self.insert_key(None, day, key)
+    }
+
+    fn import_key(&self, seq: u64, day: u32, key: DailyTracingKey) -> Result<bool, io::Error> {
+        self.insert_key(Some(seq), day, key)
+    }
+
+    fn has_key(&self, key: DailyTracingKey) -> Result<bool, io::Error> {
+        let conn = self.conn.lock().unwrap();
+        self.has_key_in(&conn, &key)
+    }
+
+    fn fetch_range(
+        &self,
+        days: RangeInclusive<u32>,
+    ) -> Result<Vec<(u32, DailyTracingKey)>, io::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare_cached(
+                "select day, key from keys where day between ?1 and ?2 order by day, seq",
+            )
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(params![days.start(), days.end()], |row| {
+                Ok((row.get(0)?, read_key(row.get(1)?)?))
+            })
+            .map_err(sql_error)?;
+        rows.collect::<Result<_, _>>().map_err(sql_error)
+    }
+
+    fn fetch_entries_since(&self, cursor: u64, limit: usize) -> Result<EntryPage, io::Error> {
+        let retained = self.retained_days();
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare_cached(
+                "select seq, day, key from keys where seq >= ?1 and day between ?2 and ?3
+                 order by seq limit ?4",
+            )
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(
+                params![
+                    cursor as i64,
+                    retained.start(),
+                    retained.end(),
+                    limit.saturating_add(1).min(i64::MAX as usize) as i64
+                ],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)? as u64,
+                        row.get(1)?,
+                        read_key(row.get(2)?)?,
+                    ))
+                },
+            )
+            .map_err(sql_error)?;
+        let entries = rows.collect::<Result<_, _>>().map_err(sql_error)?;
+        Ok(make_entry_page(entries, cursor, limit))
+    }
+
+    fn dump(&self) -> Result<Vec<(u64, u32, DailyTracingKey)>, io::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare_cached("select seq, day, key from keys order by seq")
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as u64,
+                    row.get(1)?,
+                    read_key(row.get(2)?)?,
+                ))
+            })
+            .map_err(sql_error)?;
+        rows.collect::<Result<_, _>>().map_err(sql_error)
+    }
+
+    fn day_summary(&self, day: u32) -> Result<Option<DaySummary>, io::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare_cached("select key, created from keys where day = ?1 order by seq")
+            .map_err(sql_error)?;
+        let mut rows = stmt.query(params![day]).map_err(sql_error)?;
+        let mut digest = Sha256::new();
+        let mut count = 0;
+        let mut created = 0;
+        while let Some(row) = rows.next().map_err(sql_error)? {
+            digest.input(row.get::<_, Vec<u8>>(0).map_err(sql_error)?);
+            created = created.max(row.get::<_, i64>(1).map_err(sql_error)?);
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(None);
+        }
+        Ok(Some(DaySummary {
+            count,
+            digest: digest.result().to_vec(),
+            modified: Some(UNIX_EPOCH + Duration::from_millis(created as u64)),
+        }))
+    }
+
+    fn purge(&self, before: u32) -> Result<Vec<DayInfo>, io::Error> {
+        self.remove_days(before, None)
+    }
+
+    fn archive(&self, before: u32, archive_path: &Path) -> Result<Vec<DayInfo>, io::Error> {
+        self.remove_days(before, Some(archive_path))
+    }
+}
@@ -0,0 +1,217 @@
+//! Key submissions and dummy traffic.
+//!
+//! An observer that sees a device upload to `/submit` learns that its user
+//! was infected.  Devices therefore also send fake submissions which look
+//! like real uploads: they carry random keys for the full upload window and
+//! are answered like real submissions but never stored.  So that a faster
+//! response does not give them away, the server delays fake submissions by
+//! the average duration of recent real submissions.
+//!
+//! The size of an upload would still tell how many keys it carries.
+//! Uploads are therefore padded to a fixed envelope size in the encoding
+//! they are sent in, which fits the keys of the whole retention window,
+//! and submit responses are padded to a fixed size as well.  The server
+//! rejects uploads of any other size and strips the padding after
+//! validating that it only consists of padding characters.
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+use contact_tracing::{day_number_for_timestamp, DailyTracingKey, TracingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{encode, SubmitResponseMessage};
+use crate::store::Region;
+use crate::utils::{ApiResponse, ResponseFormat};
+
+/// The number of days a device uploads keys for.
+pub const UPLOAD_DAYS: u32 = 14;
+
+/// The size of a padded upload.
+///
+/// This fits [`DAYS_WINDOW`](crate::store::DAYS_WINDOW) keys with room for
+/// the visited countries in any of the upload encodings.
+pub const ENVELOPE_SIZE: usize = 2048;
+
+/// The size of the padding in submit responses.
+pub const RESPONSE_PADDING: usize = 256;
+
+/// The character padding consists of.
+pub const PADDING_CHAR: char = '=';
+
+/// The weight of a new duration in the average submission duration.
+const DURATION_WEIGHT: f64 = 0.125;
+
+/// The body of a submission to `/submit`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DailyTracingKeyStoreRequest {
+    pub keys: Vec<(u32, DailyTracingKey)>,
+    /// The countries the user visited, used for federation.
+    #[serde(default)]
+    pub visited_countries: Vec<String>,
+    /// The region to additionally store the keys under.
+    #[serde(default)]
+    pub region: Option<Region>,
+    /// Marks dummy traffic which is validated but never stored.
+    #[serde(default)]
+    pub fake: bool,
+    /// Fills the upload up to the envelope size.
+    #[serde(default)]
+    pub padding: String,
+}
+
+impl DailyTracingKeyStoreRequest {
+    /// Creates a submission of keys.
+    pub fn new(keys: Vec<(u32, DailyTracingKey)>) -> DailyTracingKeyStoreRequest {
+        DailyTracingKeyStoreRequest {
+            keys,
+            visited_countries: vec![],
+            region: None,
+            fake: false,
+            padding: String::new(),
+        }
+    }
+
+    /// Creates a padded fake submission.
+    ///
+    /// It carries a random key for each of the last [`UPLOAD_DAYS`] days
+    /// like the upload of a device that was in use for the whole window.
+    pub fn fake() -> DailyTracingKeyStoreRequest {
+        let today = day_number_for_timestamp(&Utc::now());
+        let keys = (0..UPLOAD_DAYS)
+            .map(|offset| {
+                let day = today - offset;
+                (day, DailyTracingKey::for_day(&TracingKey::unique(), day))
+            })
+            .collect();
+        DailyTracingKeyStoreRequest {
+            fake: true,
+            ..DailyTracingKeyStoreRequest::new(keys)
+        }
+        .padded()
+    }
+
+    /// Pads the JSON encoding to a multiple of [`ENVELOPE_SIZE`] bytes.
+    ///
+    /// Uploads with up to the retention window of keys end up with exactly
+    /// one envelope.
+    pub fn padded(self) -> DailyTracingKeyStoreRequest {
+        self.pad_with(|request| serde_json::to_vec(request).map_or(0, |x| x.len()))
+    }
+
+    /// Pads the upload for the encoding it is sent in.
+    ///
+    /// MessagePack is padded for the encoding with field names which the
+    /// API replies in.  Protobuf uploads are not supported.
+    pub fn padded_for(
+        self,
+        format: ResponseFormat,
+    ) -> Result<DailyTracingKeyStoreRequest, io::Error> {
+        Ok(match format {
+            ResponseFormat::Json => self.padded(),
+            ResponseFormat::Cbor => {
+                self.pad_with(|request| serde_cbor::to_vec(request).map_or(0, |x| x.len()))
+            }
+            ResponseFormat::MessagePack => {
+                self.pad_with(|request| rmp_serde::to_vec_named(request).map_or(0, |x| x.len()))
+            }
+            ResponseFormat::Protobuf => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "protobuf uploads are not supported",
+                ))
+            }
+        })
+    }
+
+    /// Pads the encoded length to a multiple of [`ENVELOPE_SIZE`] bytes.
+    fn pad_with<F>(mut self, encoded_len: F) -> DailyTracingKeyStoreRequest
+    where
+        F: Fn(&DailyTracingKeyStoreRequest) -> usize,
+    {
+        self.padding.clear();
+        let len = encoded_len(&self);
+        let mut size = len.div_ceil(ENVELOPE_SIZE).max(1) * ENVELOPE_SIZE;
+        loop {
+            // binary encodings prefix the padding with its length which
+            // grows with it, so try the few lengths that can fit exactly
+            for pad in ((size - len).saturating_sub(8)..=size - len).rev() {
+                self.padding = PADDING_CHAR.to_string().repeat(pad);
+                if encoded_len(&self) == size {
+                    return self;
+                }
+            }
+            size += ENVELOPE_SIZE;
+        }
+    }
+
+    /// Validates and removes the padding.
+    ///
+    /// `body_len` is the size of the upload as it was sent, which has to be
+    /// exactly one envelope.
+    pub fn strip_padding(&mut self, body_len: usize) -> Result<(), io::Error> {
+        if body_len != ENVELOPE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "upload is not padded to the envelope size",
+            ));
+        }
+        if self.padding.chars().any(|c| c != PADDING_CHAR) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid padding",
+            ));
+        }
+        self.padding = String::new();
+        Ok(())
+    }
+}
+
+/// The response to a submission.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SubmitResponse {
+    /// Gives all submit responses the same size.
+    pub padding: String,
+}
+
+impl SubmitResponse {
+    /// Creates a padded response.
+    pub fn padded() -> SubmitResponse {
+        SubmitResponse {
+            padding: PADDING_CHAR.to_string().repeat(RESPONSE_PADDING),
+        }
+    }
+}
+
+impl ApiResponse for SubmitResponse {
+    fn to_protobuf(&self) -> Vec<u8> {
+        encode(&SubmitResponseMessage {
+            padding: self.padding.clone(),
+        })
+    }
+}
+
+/// Tracks how long real submissions take.
+#[derive(Debug, Default)]
+pub struct SubmissionTimer {
+    average: Mutex<Option<Duration>>,
+}
+
+impl SubmissionTimer {
+    /// Records the duration of a real submission.
+    pub fn record(&self, duration: Duration) {
+        let mut average = self.average.lock().unwrap();
+        *average = Some(match *average {
+            Some(average) => {
+                average.mul_f64(1.0 - DURATION_WEIGHT) + duration.mul_f64(DURATION_WEIGHT)
+            }
+            None => duration,
+        });
+    }
+
+    /// Returns the average duration of real submissions.
+    pub fn average(&self) -> Duration {
+        self.average.lock().unwrap().unwrap_or_default()
+    }
+}
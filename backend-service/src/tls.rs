@@ -0,0 +1,204 @@
+//! TLS termination and HTTPS requests.
+//!
+//! The server accepts TLS connections with the certificate from the config
+//! and swaps in a new certificate when the config is reloaded.  If client
+//! certificate authorities are configured, clients may present a
+//! certificate, which the admin and federation endpoints require.
+//!
+//! Requests to peers and to the replication primary go through an
+//! [`HttpClient`] which speaks both HTTP and HTTPS and presents the server
+//! certificate to peers that ask for one.
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::TcpListener as StdTcpListener;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::{stream, Stream, StreamExt};
+use hyper::client::HttpConnector;
+use hyper::Client;
+use hyper_rustls::HttpsConnector;
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig, Session,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{delay_for, timeout};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+/// How long a client may take for the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of TLS handshakes that run at the same time.
+const MAX_HANDSHAKES: usize = 128;
+
+/// How long to wait before accepting again after an error.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// A client for HTTP and HTTPS requests.
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+fn invalid_input<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// Loads the certificates of a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, io::Error> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid_input(format!("invalid certificate in {}", path.display())))?;
+    if certs.is_empty() {
+        return Err(invalid_input(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// Loads the first PKCS#8 or RSA private key of a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKey, io::Error> {
+    let invalid = || invalid_input(format!("invalid private key in {}", path.display()));
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+            .map_err(|_| invalid())?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid_input(format!("no private key in {}", path.display())))
+}
+
+/// Adds the certificates of a PEM file to a root store.
+fn add_roots(roots: &mut RootCertStore, path: &Path) -> Result<(), io::Error> {
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(|err| {
+            invalid_input(format!(
+                "invalid certificate authority in {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+fn server_config(config: &TlsConfig) -> Result<ServerConfig, io::Error> {
+    let verifier = match config.client_ca_path {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+            add_roots(&mut roots, path)?;
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut server_config = ServerConfig::new(verifier);
+    server_config
+        .set_single_cert(load_certs(&config.cert_path)?, load_key(&config.key_path)?)
+        .map_err(invalid_input)?;
+    Ok(server_config)
+}
+
+/// Accepts TLS connections with a reloadable certificate.
+pub struct TlsServer {
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl std::fmt::Debug for TlsServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TlsServer").finish()
+    }
+}
+
+impl TlsServer {
+    /// Loads the certificate and keys.
+    pub fn new(config: &TlsConfig) -> Result<TlsServer, io::Error> {
+        Ok(TlsServer {
+            config: RwLock::new(TlsServer::load(config)?),
+        })
+    }
+
+    /// Loads the certificate and keys of a config for [`replace`](Self::replace).
+    pub fn load(config: &TlsConfig) -> Result<Arc<ServerConfig>, io::Error> {
+        server_config(config).map(Arc::new)
+    }
+
+    /// Switches to newly loaded certificate and keys.
+    ///
+    /// Established connections keep their certificate.
+    pub fn replace(&self, server_config: Arc<ServerConfig>) {
+        *self.config.write().unwrap() = server_config;
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+
+    /// Accepts TLS connections on a listener.
+    ///
+    /// Failed handshakes are logged and skipped.
+    pub fn incoming(
+        self: Arc<Self>,
+        listener: StdTcpListener,
+    ) -> Result<impl Stream<Item = Result<TlsStream<TcpStream>, io::Error>>, io::Error> {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let connections = stream::unfold(listener, |mut listener| async move {
+            let rv = listener.accept().await;
+            if let Err(ref err) = rv {
+                log::error!("failed to accept connection: {}", err);
+                delay_for(ACCEPT_ERROR_DELAY).await;
+            }
+            Some((rv, listener))
+        });
+        Ok(connections
+            .filter_map(|rv| async move { rv.ok() })
+            .map(move |(stream, addr)| {
+                let acceptor = self.acceptor();
+                async move {
+                    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => Some(Ok(stream)),
+                        Ok(Err(err)) => {
+                            log::debug!("TLS handshake with {} failed: {}", addr, err);
+                            None
+                        }
+                        Err(_) => {
+                            log::debug!("TLS handshake with {} timed out", addr);
+                            None
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(MAX_HANDSHAKES)
+            .filter_map(|rv| async move { rv }))
+    }
+}
+
+/// Checks if the client of a connection presented a valid certificate.
+pub fn is_client_authenticated(stream: &TlsStream<TcpStream>) -> bool {
+    stream.get_ref().1.get_peer_certificates().is_some()
+}
+
+/// Creates a client for requests to peers and the replication primary.
+pub fn http_client(config: Option<&TlsConfig>) -> Result<HttpClient, io::Error> {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if let Some(config) = config {
+        if let Some(ref path) = config.client_ca_path {
+            add_roots(&mut client_config.root_store, path)?;
+            client_config
+                .set_single_client_cert(load_certs(&config.cert_path)?, load_key(&config.key_path)?)
+                .map_err(invalid_input)?;
+        }
+    }
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    Ok(Client::builder().build(HttpsConnector::from((http, client_config))))
+}
@@ -1,53 +1,289 @@
-use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use http::header::{HeaderValue, CONTENT_TYPE};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Stream, TryStreamExt};
+use http::header::{
+    HeaderValue, CACHE_CONTROL, CONTENT_TYPE, ETAG, LAST_MODIFIED, RETRY_AFTER, VARY,
+};
 use http::{Response, StatusCode};
 use hyper::Body;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
-use warp::{Filter, Reply};
+use warp::{Filter, Rejection, Reply};
 
-thread_local! {
-    static RESPONSE_FORMAT: Cell<ResponseFormat> = Cell::new(ResponseFormat::Json);
+use crate::store::CacheInfo;
+
+/// Types that can be sent as API response.
+///
+/// All responses serialize through serde; protobuf needs an explicit
+/// message mapping which is provided by this trait.
+pub trait ApiResponse: Serialize {
+    /// Encodes the response as protobuf message.
+    fn to_protobuf(&self) -> Vec<u8>;
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResponseFormat {
     Json,
     Cbor,
+    MessagePack,
+    Protobuf,
 }
 
-pub fn response_format() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
-    warp::header("accept")
-        .map(|value: String| {
-            RESPONSE_FORMAT.with(|format| {
-                format.set(if value.eq_ignore_ascii_case("application/x-cbor") {
-                    ResponseFormat::Cbor
-                } else {
-                    ResponseFormat::Json
-                });
-            });
+impl ResponseFormat {
+    /// Maps a media type to a response format.
+    ///
+    /// Wildcards map to JSON.
+    pub fn from_media_type(media_type: &str) -> Option<ResponseFormat> {
+        match media_type.trim().to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(ResponseFormat::Json),
+            "application/cbor" | "application/x-cbor" => Some(ResponseFormat::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(ResponseFormat::MessagePack)
+            }
+            "application/protobuf" | "application/x-protobuf" => Some(ResponseFormat::Protobuf),
+            _ => None,
+        }
+    }
+
+    /// Picks the best response format for an `Accept` header.
+    ///
+    /// Media ranges are ranked by their q-value where ties go to the range
+    /// listed first.  If nothing acceptable is supported JSON is used.
+    pub fn negotiate(accept: &str) -> ResponseFormat {
+        let mut best: Option<(f32, ResponseFormat)> = None;
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';');
+            let format = match ResponseFormat::from_media_type(parts.next().unwrap_or("")) {
+                Some(format) => format,
+                None => continue,
+            };
+            let mut q = 1.0;
+            for param in parts {
+                let mut kv = param.splitn(2, '=');
+                if kv.next().unwrap_or("").trim().eq_ignore_ascii_case("q") {
+                    q = kv.next().unwrap_or("").trim().parse().unwrap_or(0.0);
+                }
+            }
+            if q > 0.0 && best.is_none_or(|(best_q, _)| q > best_q) {
+                best = Some((q, format));
+            }
+        }
+        best.map_or(ResponseFormat::Json, |(_, format)| format)
+    }
+
+    /// Returns the content type of the format.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ResponseFormat::Json => "application/json",
+            ResponseFormat::Cbor => "application/cbor",
+            ResponseFormat::MessagePack => "application/msgpack",
+            ResponseFormat::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    /// Returns the short name of the format.
+    pub fn name(self) -> &'static str {
+        match self {
+            ResponseFormat::Json => "json",
+            ResponseFormat::Cbor => "cbor",
+            ResponseFormat::MessagePack => "msgpack",
+            ResponseFormat::Protobuf => "protobuf",
+        }
+    }
+}
+
+/// An error that is reported to the API client.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    retry_after: Option<Duration>,
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    /// Creates a new API error.
+    pub fn new<S: Into<String>>(status: StatusCode, message: S) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// Tells the client when to retry with a `Retry-After` header.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> ApiError {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Returns the status code of the error.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Converts the error into a rejection.
+    pub fn reject(self) -> Rejection {
+        warp::reject::custom(self)
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> ApiError {
+        match err.kind() {
+            io::ErrorKind::InvalidInput => ApiError::new(StatusCode::BAD_REQUEST, err.to_string()),
+            io::ErrorKind::NotFound => ApiError::new(StatusCode::NOT_FOUND, "not found"),
+            io::ErrorKind::PermissionDenied => {
+                ApiError::new(StatusCode::FORBIDDEN, err.to_string())
+            }
+            _ => {
+                log::error!("internal error: {}", err);
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+            }
+        }
+    }
+}
+
+/// Converts an I/O error into a rejection.
+pub fn reject_io(err: io::Error) -> Rejection {
+    ApiError::from(err).reject()
+}
+
+#[derive(Serialize)]
+struct ApiErrorBody<'a> {
+    error: &'a str,
+}
+
+/// Turns API errors into error responses.
+///
+/// All other rejections are passed through to warp.
+pub async fn handle_rejection(err: Rejection) -> Result<Response<Body>, Rejection> {
+    if let Some(api_error) = err.find::<ApiError>() {
+        let mut res = warp::reply::json(&ApiErrorBody {
+            error: &api_error.message,
         })
-        .untuple_one()
+        .into_response();
+        *res.status_mut() = api_error.status;
+        if let Some(retry_after) = api_error.retry_after {
+            // round up so that clients do not retry too early
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs.max(1)));
+        }
+        Ok(res)
+    } else {
+        Err(err)
+    }
 }
 
-pub fn api_reply<T>(val: T) -> ApiReply
+/// Decodes a request body in the format given by the content type.
+///
+/// Requests without content type are decoded as JSON.
+pub fn decode_body<T>(content_type: Option<&str>, body: &[u8]) -> Result<T, ApiError>
 where
-    T: Serialize,
+    T: DeserializeOwned,
+{
+    let media_type = content_type
+        .and_then(|value| value.split(';').next())
+        .unwrap_or("application/json");
+    let rv = match ResponseFormat::from_media_type(media_type) {
+        Some(ResponseFormat::Json) => serde_json::from_slice(body).map_err(|err| err.to_string()),
+        Some(ResponseFormat::Cbor) => serde_cbor::from_slice(body).map_err(|err| err.to_string()),
+        Some(ResponseFormat::MessagePack) => {
+            rmp_serde::from_slice(body).map_err(|err| err.to_string())
+        }
+        Some(ResponseFormat::Protobuf) | None => {
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported request content type",
+            ))
+        }
+    };
+    rv.map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, format!("invalid body: {}", err)))
+}
+
+/// Reads a request body of at most `max_size` bytes.
+///
+/// Bodies without `Content-Length` are accepted and rejected once they
+/// exceed the limit while being read.
+pub fn limited_body(max_size: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Copy {
+    warp::header::optional("content-length")
+        .and(warp::body::stream())
+        .and_then(move |length: Option<u64>, stream| async move {
+            let too_large =
+                || ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "request body too large").reject();
+            if length.is_some_and(|length| length > max_size) {
+                return Err(too_large());
+            }
+            read_limited(stream, max_size as usize)
+                .await?
+                .ok_or_else(too_large)
+        })
+}
+
+/// Reads a body stream, returns `None` if it is longer than `max_size`.
+async fn read_limited<S, B>(stream: S, max_size: usize) -> Result<Option<Bytes>, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    futures::pin_mut!(stream);
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream
+        .try_next()
+        .await
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err.to_string()).reject())?
+    {
+        if body.len() + chunk.remaining() > max_size {
+            return Ok(None);
+        }
+        while chunk.has_remaining() {
+            let len = chunk.bytes().len();
+            body.extend_from_slice(chunk.bytes());
+            chunk.advance(len);
+        }
+    }
+    Ok(Some(body.freeze()))
+}
+
+/// Extracts the negotiated response format from the `Accept` header.
+pub fn response_format() -> impl Filter<Extract = (ResponseFormat,), Error = warp::Rejection> + Copy
+{
+    warp::header::optional("accept").map(|value: Option<String>| {
+        value.map_or(ResponseFormat::Json, |value| {
+            ResponseFormat::negotiate(&value)
+        })
+    })
+}
+
+pub fn api_reply<T>(format: ResponseFormat, val: T) -> ApiReply
+where
+    T: ApiResponse,
 {
     ApiReply {
-        inner: match RESPONSE_FORMAT.with(|x| x.get()) {
+        format,
+        inner: match format {
             ResponseFormat::Json => ::serde_json::to_vec(&val).map_err(|err| {
                 log::error!("Invalid json serialization: {:?}", err);
             }),
             ResponseFormat::Cbor => ::serde_cbor::to_vec(&val).map_err(|err| {
                 log::error!("Invalid cbor serialization: {:?}", err);
             }),
+            ResponseFormat::MessagePack => ::rmp_serde::to_vec_named(&val).map_err(|err| {
+                log::error!("Invalid msgpack serialization: {:?}", err);
+            }),
+            ResponseFormat::Protobuf => Ok(val.to_protobuf()),
         },
     }
 }
 
 /// An API response.
 pub struct ApiReply {
+    format: ResponseFormat,
     inner: Result<Vec<u8>, ()>,
 }
 
@@ -57,11 +293,101 @@ impl Reply for ApiReply {
         match self.inner {
             Ok(body) => {
                 let mut res = Response::new(body.into());
-                res.headers_mut()
-                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                let headers = res.headers_mut();
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(self.format.content_type()),
+                );
+                headers.insert(VARY, HeaderValue::from_static("Accept"));
                 res
             }
             Err(()) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 }
+
+/// The conditional request headers of a request.
+#[derive(Debug, Default)]
+pub struct CacheConditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+}
+
+impl CacheConditions {
+    fn is_not_modified(&self, etag: &str, last_modified: Option<SystemTime>) -> bool {
+        // If-Modified-Since is ignored if If-None-Match is present
+        if let Some(ref if_none_match) = self.if_none_match {
+            return if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            });
+        }
+        match (self.if_modified_since, last_modified) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+}
+
+/// Extracts the conditional request headers.
+pub fn cache_conditions(
+) -> impl Filter<Extract = (CacheConditions,), Error = warp::Rejection> + Copy {
+    warp::header::optional("if-none-match")
+        .and(warp::header::optional("if-modified-since"))
+        .map(
+            |if_none_match: Option<String>, if_modified_since: Option<String>| CacheConditions {
+                if_none_match,
+                if_modified_since: if_modified_since
+                    .and_then(|value| httpdate::parse_http_date(&value).ok()),
+            },
+        )
+}
+
+/// Truncates a timestamp to the second precision of HTTP dates.
+fn truncate_to_seconds(ts: SystemTime) -> SystemTime {
+    let secs = ts.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Replies with a cacheable API response.
+///
+/// If the request's conditional headers match the cache info an empty
+/// `304 Not Modified` response is sent and the value is never computed.
+pub async fn cached_api_reply<T, F, Fut>(
+    format: ResponseFormat,
+    info: &CacheInfo,
+    max_age: u64,
+    conditions: &CacheConditions,
+    f: F,
+) -> Result<Response<Body>, Rejection>
+where
+    T: ApiResponse,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Rejection>>,
+{
+    let etag = format!("\"{}-{}\"", info.digest, format.name());
+    let last_modified = info.last_modified.map(truncate_to_seconds);
+
+    let mut res = if conditions.is_not_modified(&etag, last_modified) {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::NOT_MODIFIED;
+        res
+    } else {
+        api_reply(format, f().await?).into_response()
+    };
+
+    let headers = res.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, value);
+    }
+    if let Some(last_modified) = last_modified {
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(last_modified)) {
+            headers.insert(LAST_MODIFIED, value);
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!("public, max-age={}", max_age)) {
+        headers.insert(CACHE_CONTROL, value);
+    }
+    headers.insert(VARY, HeaderValue::from_static("Accept"));
+    Ok(res)
+}
@@ -0,0 +1,171 @@
+//! Helpers for tests that run `backend-service` processes.
+#![allow(dead_code)]
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use backend_service::submission::DailyTracingKeyStoreRequest;
+use contact_tracing::{day_number_for_timestamp, DailyTracingKey, TracingKey};
+use hyper::{Body, Client, Request, StatusCode};
+use serde_json::Value;
+
+/// A `backend-service` process that is killed when dropped.
+pub struct Instance {
+    child: Child,
+    pub url: String,
+}
+
+impl Instance {
+    /// Starts an instance with its store in `dir/name`.
+    ///
+    /// `extra_config` is appended to the YAML config.
+    pub fn start(dir: &Path, name: &str, port: u16, extra_config: &str) -> Instance {
+        let config_path = Instance::write_config(dir, name, port, extra_config);
+        let child = Command::new(env!("CARGO_BIN_EXE_backend-service"))
+            .arg("--config")
+            .arg(&config_path)
+            .spawn()
+            .unwrap();
+        Instance {
+            child,
+            url: format!("http://127.0.0.1:{}", port),
+        }
+    }
+}
+
+impl Instance {
+    /// Writes the config of an instance and returns its path.
+    pub fn write_config(dir: &Path, name: &str, port: u16, extra_config: &str) -> PathBuf {
+        let config = format!(
+            "listen: 127.0.0.1:{}\ndb_path: {}\nfsync: false\n{}",
+            port,
+            dir.join(name).display(),
+            extra_config
+        );
+        let config_path = dir.join(format!("{}.yml", name));
+        fs::write(&config_path, config).unwrap();
+        config_path
+    }
+
+    /// Sends a signal like `TERM` or `HUP` to the process.
+    pub fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(self.child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Waits for the process to exit.
+    pub fn wait(&mut self) -> ExitStatus {
+        self.child.wait().unwrap()
+    }
+
+    /// Waits for the process to exit and fails if it takes too long.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> ExitStatus {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "process did not exit");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+pub async fn request(method: &str, url: &str, body: Option<Value>) -> Option<(StatusCode, Value)> {
+    request_with_token(method, url, None, body).await
+}
+
+/// Sends a request with a bearer token.
+pub async fn request_with_token(
+    method: &str,
+    url: &str,
+    token: Option<&str>,
+    body: Option<Value>,
+) -> Option<(StatusCode, Value)> {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        req = req.header("authorization", format!("Bearer {}", token));
+    }
+    let req = req
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .unwrap();
+    let res = Client::new().request(req).await.ok()?;
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.ok()?;
+    Some((status, serde_json::from_slice(&body).unwrap_or(Value::Null)))
+}
+
+/// Polls a URL until the check passes.
+pub async fn wait_for<F: Fn(&Value) -> bool>(url: &str, check: F) -> Value {
+    wait_for_with_token(url, None, check).await
+}
+
+/// Polls a URL with a bearer token until the check passes.
+pub async fn wait_for_with_token<F: Fn(&Value) -> bool>(
+    url: &str,
+    token: Option<&str>,
+    check: F,
+) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        if let Some((StatusCode::OK, value)) = request_with_token("GET", url, token, None).await {
+            if check(&value) {
+                return value;
+            }
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for {}", url);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+/// Creates random keys for the last three days.
+pub fn random_keys(count: u32) -> Vec<(u32, DailyTracingKey)> {
+    let today = day_number_for_timestamp(&chrono::Utc::now());
+    (0..count)
+        .map(|i| {
+            let day = today - i % 3;
+            (day, DailyTracingKey::for_day(&TracingKey::unique(), day))
+        })
+        .collect()
+}
+
+/// Encodes a submission padded to the envelope size.
+pub fn padded_body(request: DailyTracingKeyStoreRequest) -> Value {
+    serde_json::to_value(request.padded()).unwrap()
+}
+
+pub async fn submit_keys(url: &str, count: u32) -> StatusCode {
+    let body = padded_body(DailyTracingKeyStoreRequest::new(random_keys(count)));
+    request("POST", &format!("{}/submit", url), Some(body))
+        .await
+        .unwrap()
+        .0
+}
+
+pub fn key_count(page: &Value) -> usize {
+    page["keys"].as_array().map_or(0, |keys| keys.len())
+}
@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use backend_service::store::{FileKeyStore, FileStoreOptions, KeyStore};
+use contact_tracing::{DailyTracingKey, TracingKey};
+
+const WRITERS: usize = 8;
+const KEYS_PER_WRITER: usize = 200;
+
+#[test]
+fn test_concurrent_submit_and_fetch() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = FileStoreOptions {
+        fsync: false,
+        ..FileStoreOptions::default()
+    };
+    let store = Arc::new(FileKeyStore::open_with_options(dir.path(), options).unwrap());
+    let today = store.current_day();
+    let done = Arc::new(AtomicBool::new(false));
+
+    // a key that every writer tries to submit
+    let contested = DailyTracingKey::for_day(&TracingKey::unique(), today);
+
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|idx| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut added = vec![];
+                for i in 0..KEYS_PER_WRITER {
+                    let day = today - ((idx + i) % 4) as u32;
+                    let key = DailyTracingKey::for_day(&TracingKey::unique(), day);
+                    assert!(store.add_key(day, key).unwrap());
+                    added.push(key);
+                    if i == KEYS_PER_WRITER / 2 && store.add_key(today, contested).unwrap() {
+                        added.push(contested);
+                    }
+                }
+                added
+            })
+        })
+        .collect();
+
+    // cursor readers must see every key exactly once, even while keys are
+    // being written to several buckets concurrently
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut seen = HashSet::new();
+                let mut cursor = 0;
+                loop {
+                    let finished = done.load(Ordering::SeqCst);
+                    let page = store.fetch_since(cursor, 50).unwrap();
+                    for (_, key) in page.keys {
+                        assert!(seen.insert(key), "key returned twice");
+                    }
+                    cursor = page.next_cursor;
+                    store.cache_info(today - 3..=today).unwrap();
+                    store.fetch_range(today - 3..=today).unwrap();
+                    if finished && !page.has_more {
+                        break;
+                    }
+                }
+                seen
+            })
+        })
+        .collect();
+
+    let mut expected = HashSet::new();
+    for writer in writers {
+        for key in writer.join().unwrap() {
+            assert!(expected.insert(key));
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+
+    assert_eq!(expected.len(), WRITERS * KEYS_PER_WRITER + 1);
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), expected);
+    }
+
+    let stored: HashSet<_> = store
+        .fetch_range(today - 3..=today)
+        .unwrap()
+        .into_iter()
+        .map(|(_, key)| key)
+        .collect();
+    assert_eq!(stored, expected);
+
+    // everything survives a reopen
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(
+        store.fetch_since(0, usize::MAX).unwrap().keys.len(),
+        expected.len()
+    );
+}
@@ -0,0 +1,33 @@
+use backend_service::export::{ExportArchive, ExportSigner};
+use chrono::{TimeZone, Utc};
+use contact_tracing::{DailyTracingKey, TracingKey};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+fn make_signer() -> ExportSigner {
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+        .unwrap();
+    ExportSigner::from_pkcs8(pkcs8.as_ref(), "310", "v1").unwrap()
+}
+
+#[test]
+fn test_export_roundtrip() {
+    let signer = make_signer();
+    let tkey = TracingKey::unique();
+    let archive = ExportArchive {
+        region: "CH".into(),
+        start_timestamp: Utc.timestamp(1_587_600_000, 0),
+        end_timestamp: Utc.timestamp(1_587_686_400, 0),
+        keys: vec![
+            (18375, DailyTracingKey::for_day(&tkey, 18375)),
+            (18376, DailyTracingKey::for_day(&tkey, 18376)),
+        ],
+    };
+
+    let zip = archive.to_zip(&signer).unwrap();
+    let parsed = ExportArchive::from_zip(&zip, Some(signer.public_key())).unwrap();
+    assert_eq!(parsed, archive);
+
+    let other_signer = make_signer();
+    assert!(ExportArchive::from_zip(&zip, Some(other_signer.public_key())).is_err());
+}
@@ -0,0 +1,177 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use hyper::StatusCode;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use serde_json::{json, Value};
+
+use backend_service::submission::DailyTracingKeyStoreRequest;
+
+mod common;
+
+use common::{
+    free_port, key_count, padded_body, random_keys, request, request_with_token, wait_for, Instance,
+};
+
+/// Writes a new signing key and returns its base64 encoded public key.
+fn write_signing_key(path: &Path) -> String {
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+        .unwrap();
+    fs::write(path, pkcs8.as_ref()).unwrap();
+    let key_pair =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+    base64::encode(key_pair.public_key())
+}
+
+/// Returns the federation token of a region.
+fn token(region: &str) -> String {
+    format!("{}-secret", region)
+}
+
+fn federation_config(region: &str, signing_key: &Path, peer: (&str, u16, &str)) -> String {
+    format!(
+        "federation:\n  region: {}\n  signing_key: {}\n  interval: 1\n  token: {}\n  peers:\n    - region: {}\n      url: http://127.0.0.1:{}\n      public_key: {}\n      token: {}\n",
+        region,
+        signing_key.display(),
+        token(region),
+        peer.0,
+        peer.1,
+        peer.2,
+        token(peer.0)
+    )
+}
+
+async fn fetch_all(url: &str) -> Value {
+    request("GET", &format!("{}/fetch?since=0", url), None)
+        .await
+        .unwrap()
+        .1
+}
+
+#[tokio::test]
+async fn test_federation() {
+    let dir = tempfile::tempdir().unwrap();
+    let ch_key_path = dir.path().join("ch.pk8");
+    let de_key_path = dir.path().join("de.pk8");
+    let ch_public_key = write_signing_key(&ch_key_path);
+    let de_public_key = write_signing_key(&de_key_path);
+    let (ch_port, de_port) = (free_port(), free_port());
+    let ch = Instance::start(
+        dir.path(),
+        "ch",
+        ch_port,
+        &federation_config("CH", &ch_key_path, ("DE", de_port, &de_public_key)),
+    );
+    let de = Instance::start(
+        dir.path(),
+        "de",
+        de_port,
+        &format!(
+            "{}  max_body_size: 16384\n",
+            federation_config("DE", &de_key_path, ("CH", ch_port, &ch_public_key))
+        ),
+    );
+
+    let fetch_url = format!("{}/fetch?since=0", ch.url);
+    wait_for(&fetch_url, |_| true).await;
+    let mut body = DailyTracingKeyStoreRequest::new(random_keys(10));
+    body.visited_countries = vec!["DE".into()];
+    let body = padded_body(body);
+    let (status, _) = request("POST", &format!("{}/submit", ch.url), Some(body))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let body = padded_body(DailyTracingKeyStoreRequest::new(random_keys(5)));
+    let (status, _) = request("POST", &format!("{}/submit", de.url), Some(body))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+
+    // keys arrive in both directions
+    wait_for(&format!("{}/fetch?since=0", de.url), |page| {
+        key_count(page) == 15
+    })
+    .await;
+    wait_for(&fetch_url, |page| key_count(page) == 15).await;
+
+    // further exchanges neither echo nor duplicate keys
+    tokio::time::delay_for(Duration::from_secs(3)).await;
+    assert_eq!(key_count(&fetch_all(&ch.url).await), 15);
+    assert_eq!(key_count(&fetch_all(&de.url).await), 15);
+
+    // batches only carry the keys of their origin
+    let batches_url = format!("{}/federation/batches?since=0", ch.url);
+    let (status, _) = request("GET", &batches_url, None).await.unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, signed) = request_with_token("GET", &batches_url, Some(&token("CH")), None)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let batch: Value =
+        serde_json::from_slice(&base64::decode(signed["batch"].as_str().unwrap()).unwrap())
+            .unwrap();
+    assert_eq!(batch["origin"], "CH");
+    assert_eq!(batch["has_more"], false);
+    let keys = batch["keys"].as_array().unwrap();
+    assert_eq!(keys.len(), 10);
+    assert!(keys.iter().all(|key| key["visited"] == json!(["DE"])));
+
+    // uploading a known batch again adds nothing
+    let upload_url = format!("{}/federation/upload", de.url);
+    let de_token = token("DE");
+    let de_token = Some(de_token.as_str());
+    let (status, response) =
+        request_with_token("POST", &upload_url, de_token, Some(signed.clone()))
+            .await
+            .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["added"], 0);
+
+    // tampered batches are rejected
+    let mut tampered_batch = batch.clone();
+    tampered_batch["keys"][0]["day"] = json!(0);
+    let mut tampered = signed.clone();
+    tampered["batch"] = json!(base64::encode(tampered_batch.to_string()));
+    let (status, _) = request_with_token("POST", &upload_url, de_token, Some(tampered))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // as are batches from regions that are not peers
+    let (status, _) = request_with_token(
+        "POST",
+        &format!("{}/federation/upload", ch.url),
+        Some(&token("CH")),
+        Some(signed),
+    )
+    .await
+    .unwrap();
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(key_count(&fetch_all(&de.url).await), 15);
+
+    // uploads are limited in size
+    let huge = json!({ "batch": "A".repeat(32 * 1024), "signature": "" });
+    let (status, _) = request_with_token("POST", &upload_url, de_token, Some(huge))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn test_federation_requires_auth() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_path = dir.path().join("ch.pk8");
+    let public_key = write_signing_key(&key_path);
+    let config = format!(
+        "federation:\n  region: CH\n  signing_key: {}\n  peers:\n    - region: DE\n      url: http://127.0.0.1:{}\n      public_key: {}\n",
+        key_path.display(),
+        free_port(),
+        public_key
+    );
+
+    // peers could never reach the closed federation endpoints
+    let mut instance = Instance::start(dir.path(), "ch", free_port(), &config);
+    assert!(!instance.wait_timeout(Duration::from_secs(10)).success());
+}
@@ -0,0 +1,59 @@
+use backend_service::utils::{decode_body, ResponseFormat};
+use contact_tracing::{DailyTracingKey, TracingKey};
+
+#[test]
+fn test_negotiate_format() {
+    assert_eq!(ResponseFormat::negotiate("*/*"), ResponseFormat::Json);
+    assert_eq!(
+        ResponseFormat::negotiate("application/x-cbor"),
+        ResponseFormat::Cbor
+    );
+    assert_eq!(
+        ResponseFormat::negotiate("Application/CBOR"),
+        ResponseFormat::Cbor
+    );
+    assert_eq!(
+        ResponseFormat::negotiate("application/json;q=0.5, application/msgpack"),
+        ResponseFormat::MessagePack
+    );
+    assert_eq!(
+        ResponseFormat::negotiate("application/x-protobuf;q=0.9, application/cbor;q=0.1"),
+        ResponseFormat::Protobuf
+    );
+    assert_eq!(
+        ResponseFormat::negotiate("application/cbor;q=0, text/html"),
+        ResponseFormat::Json
+    );
+    assert_eq!(
+        ResponseFormat::negotiate("text/html, application/cbor;q=0.2, */*;q=0.1"),
+        ResponseFormat::Cbor
+    );
+}
+
+#[test]
+fn test_decode_binary_bodies() {
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), 18375);
+    let keys = vec![(18375u32, key)];
+
+    // binary formats carry the raw key bytes instead of base64
+    let cbor = serde_cbor::to_vec(&keys).unwrap();
+    assert!(cbor.windows(16).any(|x| x == key.as_bytes()));
+    let msgpack = rmp_serde::to_vec(&keys).unwrap();
+    assert!(msgpack.windows(16).any(|x| x == key.as_bytes()));
+
+    for (content_type, body) in &[
+        (None, serde_json::to_vec(&keys).unwrap()),
+        (
+            Some("application/json; charset=utf-8"),
+            serde_json::to_vec(&keys).unwrap(),
+        ),
+        (Some("application/cbor"), cbor),
+        (Some("application/x-msgpack"), msgpack),
+    ] {
+        let decoded: Vec<(u32, DailyTracingKey)> = decode_body(*content_type, body).unwrap();
+        assert_eq!(decoded, keys);
+    }
+
+    assert!(decode_body::<Vec<(u32, DailyTracingKey)>>(Some("text/plain"), b"").is_err());
+    assert!(decode_body::<Vec<(u32, DailyTracingKey)>>(Some("application/cbor"), b"{").is_err());
+}
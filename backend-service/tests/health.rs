@@ -0,0 +1,61 @@
+use std::fs;
+use std::io;
+
+use hyper::StatusCode;
+use serde_json::json;
+
+use backend_service::health::check_readiness;
+use backend_service::store::{AsyncKeyStore, MemoryKeyStore};
+
+mod common;
+
+use common::{free_port, request, wait_for, Instance};
+
+#[tokio::test]
+async fn test_health_and_readiness() {
+    let dir = tempfile::tempdir().unwrap();
+    let instance = Instance::start(dir.path(), "backend", free_port(), "");
+
+    let health = wait_for(&format!("{}/healthz", instance.url), |_| true).await;
+    assert_eq!(health, json!({ "status": "ok" }));
+    let readyz_url = format!("{}/readyz", instance.url);
+    let readiness = wait_for(&readyz_url, |_| true).await;
+    assert_eq!(readiness, json!({ "ready": true, "failures": [] }));
+
+    // a storage folder that went away makes the instance unready
+    fs::remove_dir_all(dir.path().join("backend")).unwrap();
+    let (status, readiness) = request("GET", &readyz_url, None).await.unwrap();
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(readiness["ready"], false);
+    // without telling the public where the storage lives
+    let failures = readiness["failures"].as_array().unwrap();
+    assert!(failures.contains(&json!("storage is not writable")));
+    assert!(!readiness.to_string().contains(dir.path().to_str().unwrap()));
+
+    // but it is still alive
+    let (status, _) = request("GET", &format!("{}/healthz", instance.url), None)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_readiness_checks() {
+    let store = AsyncKeyStore::new(MemoryKeyStore::new());
+    assert!(check_readiness(&store, None).await.ready);
+
+    // bad requests are not storage failures
+    let rv = store
+        .run(|_| Err::<(), _>(io::Error::new(io::ErrorKind::InvalidInput, "bad key")))
+        .await;
+    assert!(rv.is_err());
+    assert!(check_readiness(&store, None).await.ready);
+
+    let rv = store
+        .run(|_| Err::<(), _>(io::Error::other("disk on fire")))
+        .await;
+    assert!(rv.is_err());
+    let readiness = check_readiness(&store, None).await;
+    assert!(!readiness.ready);
+    assert_eq!(readiness.failures, vec!["recent storage error"]);
+}
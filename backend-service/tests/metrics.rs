@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use hyper::{Body, Client, Request, StatusCode};
+
+use backend_service::metrics::Metrics;
+use backend_service::store::DayInfo;
+use backend_service::submission::DailyTracingKeyStoreRequest;
+
+mod common;
+
+use common::{free_port, request, submit_keys, wait_for, Instance};
+
+#[test]
+fn test_render_metrics() {
+    let metrics = Metrics::default();
+    metrics.submission_accepted(3);
+    metrics.submission_rejected("invalid_body");
+    metrics.submission_rejected("invalid_body");
+    metrics.fetch_request("json", 200, Duration::from_millis(3));
+    metrics.fetch_request("json", 304, Duration::from_secs(20));
+    metrics.bucket_loaded(Duration::from_micros(100));
+
+    let days = vec![DayInfo {
+        day: 18400,
+        count: 42,
+    }];
+    let text = metrics.render(&days, 1234);
+    for line in &[
+        "# TYPE backend_submissions_accepted_total counter",
+        "backend_submissions_accepted_total 1",
+        "backend_keys_accepted_total 3",
+        "backend_submissions_rejected_total{reason=\"invalid_body\"} 2",
+        "backend_fetch_requests_total{format=\"json\",status=\"200\"} 1",
+        "backend_fetch_requests_total{format=\"json\",status=\"304\"} 1",
+        "backend_fetch_duration_seconds_bucket{format=\"json\",le=\"0.0025\"} 0",
+        "backend_fetch_duration_seconds_bucket{format=\"json\",le=\"0.005\"} 1",
+        "backend_fetch_duration_seconds_bucket{format=\"json\",le=\"10\"} 1",
+        "backend_fetch_duration_seconds_bucket{format=\"json\",le=\"+Inf\"} 2",
+        "backend_fetch_duration_seconds_count{format=\"json\"} 2",
+        "backend_bucket_load_duration_seconds_bucket{le=\"0.0005\"} 1",
+        "backend_bucket_load_duration_seconds_count 1",
+        "backend_keys{day=\"18400\"} 42",
+        "backend_storage_bytes 1234",
+    ] {
+        assert!(text.lines().any(|l| l == *line), "missing {}", line);
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let instance = Instance::start(dir.path(), "backend", free_port(), "admin_token: admin\n");
+    let fetch_url = format!("{}/fetch?since=0", instance.url);
+    wait_for(&fetch_url, |_| true).await;
+    assert_eq!(submit_keys(&instance.url, 3).await, StatusCode::OK);
+    let fake = serde_json::to_value(DailyTracingKeyStoreRequest::fake()).unwrap();
+    let (status, _) = request("POST", &format!("{}/submit", instance.url), Some(fake))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request("POST", &format!("{}/submit", instance.url), None)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let metrics_url = format!("{}/metrics", instance.url);
+    let (status, _) = request("GET", &metrics_url, None).await.unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let req = Request::get(metrics_url)
+        .header("authorization", "Bearer admin")
+        .body(Body::empty())
+        .unwrap();
+    let res = Client::new().request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    // fake submissions are indistinguishable from real ones
+    assert!(text.contains("backend_submissions_accepted_total 2\n"));
+    assert!(!text.contains("fake_submissions"));
+    assert!(text.contains("backend_submissions_rejected_total{reason=\"invalid_body\"} 1\n"));
+    assert!(text.contains("backend_fetch_requests_total{format=\"json\",status=\"200\"}"));
+    let keys: u64 = text
+        .lines()
+        .filter(|line| line.starts_with("backend_keys{"))
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+        .sum();
+    assert_eq!(keys, 3);
+    let size = text
+        .lines()
+        .find(|line| line.starts_with("backend_storage_bytes "))
+        .unwrap();
+    assert_ne!(size, "backend_storage_bytes 0");
+}
+
+#[tokio::test]
+async fn test_admin_endpoints_closed() {
+    let dir = tempfile::tempdir().unwrap();
+    let instance = Instance::start(dir.path(), "backend", free_port(), "");
+    wait_for(&format!("{}/healthz", instance.url), |_| true).await;
+
+    // without a client certificate authority or a token nobody gets in
+    for path in &[
+        "metrics",
+        "snapshot",
+        "replication/status",
+        "replication/stream?since=0",
+        "federation/batches?since=0",
+    ] {
+        let (status, _) = request("GET", &format!("{}/{}", instance.url, path), None)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+    }
+}
@@ -0,0 +1,107 @@
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use backend_service::store::Region;
+use backend_service::submission::DailyTracingKeyStoreRequest;
+
+mod common;
+
+use common::{free_port, key_count, padded_body, random_keys, request, wait_for, Instance};
+
+async fn submit_to_region(url: &str, count: u32, region: Option<&str>) -> Value {
+    let keys = random_keys(count);
+    let mut submission = DailyTracingKeyStoreRequest::new(keys.clone());
+    submission.region = region.map(|region| region.parse().unwrap());
+    let body = padded_body(submission);
+    let (status, _) = request("POST", &format!("{}/submit", url), Some(body))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    json!(keys)
+}
+
+async fn get(url: &str) -> (StatusCode, Value) {
+    request("GET", url, None).await.unwrap()
+}
+
+#[test]
+fn test_region_codes() {
+    assert_eq!("CH".parse::<Region>().unwrap().as_str(), "CH");
+    for bad in &["", "ch", "CHE", "C1", "../"] {
+        assert!(bad.parse::<Region>().is_err());
+    }
+    assert!(serde_json::from_str::<Region>("\"de\"").is_err());
+}
+
+#[tokio::test]
+async fn test_region_partitions() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let instance = Instance::start(dir.path(), "backend", port, "");
+    wait_for(&format!("{}/fetch?since=0", instance.url), |_| true).await;
+
+    let ch_keys = submit_to_region(&instance.url, 6, Some("CH")).await;
+    submit_to_region(&instance.url, 4, Some("DE")).await;
+    submit_to_region(&instance.url, 2, None).await;
+    let mut body = DailyTracingKeyStoreRequest::new(random_keys(1));
+    body.region = Some("CH".parse().unwrap());
+    let mut body = padded_body(body);
+    body["region"] = json!("ch");
+    let (status, _) = request("POST", &format!("{}/submit", instance.url), Some(body))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // the global set still has all keys
+    let (_, page) = get(&format!("{}/fetch?since=0", instance.url)).await;
+    assert_eq!(key_count(&page), 12);
+
+    let (status, page) = get(&format!("{}/fetch/CH?since=0", instance.url)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(key_count(&page), 6);
+    let (_, page) = get(&format!("{}/fetch/DE?since=0", instance.url)).await;
+    assert_eq!(key_count(&page), 4);
+
+    let since = chrono::Utc::now().timestamp() - 7 * 24 * 3600;
+    let (status, keys) = get(&format!("{}/fetch/CH/{}", instance.url, since)).await;
+    assert_eq!(status, StatusCode::OK);
+    let mut keys = keys.as_array().unwrap().clone();
+    let mut expected = ch_keys.as_array().unwrap().clone();
+    keys.sort_by_key(|key| key.to_string());
+    expected.sort_by_key(|key| key.to_string());
+    assert_eq!(keys, expected);
+    for url in &[
+        format!("{}/fetch/CH/{}", instance.url, u64::MAX),
+        format!("{}/fetch/{}", instance.url, i64::MAX),
+    ] {
+        let (status, _) = get(url).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    let (status, days) = get(&format!("{}/fetch/DE/days", instance.url)).await;
+    assert_eq!(status, StatusCode::OK);
+    let count: u64 = days
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|info| info["count"].as_u64().unwrap())
+        .sum();
+    assert_eq!(count, 4);
+    let day = days[0]["day"].as_u64().unwrap();
+    let (status, _) = get(&format!("{}/fetch/DE/days/{}", instance.url, day)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // regions without keys are empty and invalid regions do not exist
+    let (status, page) = get(&format!("{}/fetch/FR?since=0", instance.url)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(key_count(&page), 0);
+    let (status, _) = get(&format!("{}/fetch/fr?since=0", instance.url)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // partitions are found again after a restart
+    drop(instance);
+    let instance = Instance::start(dir.path(), "backend", port, "");
+    let page = wait_for(&format!("{}/fetch/CH?since=0", instance.url), |_| true).await;
+    assert_eq!(key_count(&page), 6);
+    assert!(dir.path().join("backend/regions/DE").is_dir());
+}
@@ -0,0 +1,204 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use backend_service::config::RateConfig;
+use backend_service::ratelimit::RateLimiter;
+use backend_service::submission::DailyTracingKeyStoreRequest;
+use hyper::{Body, Client, Request, StatusCode};
+
+use common::{free_port, padded_body, random_keys, wait_for, Instance};
+
+#[test]
+fn test_token_bucket() {
+    let limiter = RateLimiter::new(
+        Some(RateConfig {
+            rate: 2.0,
+            burst: 3,
+        }),
+        10,
+    );
+    let now = Instant::now();
+    for _ in 0..3 {
+        assert_eq!(limiter.check_at(&"a", now), Ok(()));
+    }
+    assert_eq!(limiter.check_at(&"a", now), Err(Duration::from_millis(500)));
+    // other clients have their own bucket
+    assert_eq!(limiter.check_at(&"b", now), Ok(()));
+
+    let later = now + Duration::from_millis(500);
+    assert_eq!(limiter.check_at(&"a", later), Ok(()));
+    assert!(limiter.check_at(&"a", later).is_err());
+
+    let disabled = RateLimiter::new(None, 10);
+    for _ in 0..100 {
+        assert_eq!(disabled.check_at(&"a", now), Ok(()));
+    }
+    assert_eq!(disabled.clients(), 0);
+}
+
+#[test]
+fn test_bounded_clients() {
+    let limiter = RateLimiter::new(
+        Some(RateConfig {
+            rate: 1.0,
+            burst: 1,
+        }),
+        3,
+    );
+    let now = Instant::now();
+    for client in 0..10 {
+        let at = now + Duration::from_millis(client * 10);
+        assert_eq!(limiter.check_at(&client, at), Ok(()));
+        assert!(limiter.clients() <= 3);
+    }
+    // the least recently used clients were dropped
+    let at = now + Duration::from_millis(100);
+    assert!(limiter.check_at(&9, at).is_err());
+    assert_eq!(limiter.check_at(&0, at), Ok(()));
+
+    // refilled buckets are dropped before used ones
+    let at = now + Duration::from_secs(10);
+    for client in 10..13 {
+        assert_eq!(limiter.check_at(&client, at), Ok(()));
+    }
+    assert_eq!(limiter.clients(), 3);
+    assert!(limiter.check_at(&12, at).is_err());
+
+    // a full table is pruned to 90% at once
+    let limiter = RateLimiter::new(
+        Some(RateConfig {
+            rate: 1.0,
+            burst: 1,
+        }),
+        100,
+    );
+    for client in 0..100 {
+        let at = now + Duration::from_millis(client);
+        assert_eq!(limiter.check_at(&client, at), Ok(()));
+    }
+    assert_eq!(limiter.clients(), 100);
+    let at = now + Duration::from_millis(100);
+    assert_eq!(limiter.check_at(&100, at), Ok(()));
+    assert_eq!(limiter.clients(), 91);
+    // the most recently used clients were kept
+    assert!(limiter.check_at(&10, at).is_err());
+    assert_eq!(limiter.check_at(&9, at), Ok(()));
+}
+
+async fn submit(url: &str, token: Option<&str>, count: u32) -> (StatusCode, Option<String>) {
+    let body = padded_body(DailyTracingKeyStoreRequest::new(random_keys(count)));
+    let mut req = Request::builder()
+        .method("POST")
+        .uri(format!("{}/submit", url))
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        req = req.header("x-upload-token", token);
+    }
+    let res = Client::new()
+        .request(req.body(Body::from(body.to_string())).unwrap())
+        .await
+        .unwrap();
+    let retry_after = res
+        .headers()
+        .get("retry-after")
+        .map(|value| value.to_str().unwrap().to_string());
+    (res.status(), retry_after)
+}
+
+async fn fetch_status(url: &str) -> StatusCode {
+    Client::new()
+        .get(format!("{}/days", url).parse().unwrap())
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn fetch_status_forwarded(url: &str, forwarded_for: &str) -> StatusCode {
+    let req = Request::builder()
+        .uri(format!("{}/days", url))
+        .header("x-forwarded-for", forwarded_for)
+        .body(Body::empty())
+        .unwrap();
+    Client::new().request(req).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn test_rate_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let instance = Instance::start(
+        dir.path(),
+        "limited",
+        port,
+        "admin_token: admin\nreplication:\n  token: secret\nlimits:\n  max_body_size: 4096\n  submit_per_token:\n    rate: 0.01\n    burst: 2\n  fetch_per_ip:\n    rate: 0.01\n    burst: 3\n",
+    );
+    let url = &instance.url;
+    wait_for(&format!("{}/healthz", url), |_| true).await;
+
+    assert_eq!(submit(url, Some("a"), 2).await, (StatusCode::OK, None));
+    assert_eq!(submit(url, Some("a"), 2).await, (StatusCode::OK, None));
+    let (status, retry_after) = submit(url, Some("a"), 2).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(retry_after.unwrap().parse::<u64>().unwrap() > 60);
+
+    // other tokens and submissions without token are not limited
+    assert_eq!(submit(url, Some("b"), 2).await.0, StatusCode::OK);
+    assert_eq!(submit(url, None, 2).await.0, StatusCode::OK);
+
+    assert_eq!(
+        submit(url, Some("c"), 500).await.0,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    for _ in 0..3 {
+        assert_eq!(fetch_status(url).await, StatusCode::OK);
+    }
+    assert_eq!(fetch_status(url).await, StatusCode::TOO_MANY_REQUESTS);
+
+    // exports and full dumps of the store share the fetch limit
+    let status = Client::new()
+        .get(format!("{}/export/0", url).parse().unwrap())
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    for (path, token) in &[
+        ("snapshot", "admin"),
+        ("replication/stream?since=0", "secret"),
+    ] {
+        let req = Request::get(format!("{}/{}", url, path))
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        let status = Client::new().request(req).await.unwrap().status();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
+
+#[tokio::test]
+async fn test_forwarded_for() {
+    let dir = tempfile::tempdir().unwrap();
+    let instance = Instance::start(
+        dir.path(),
+        "proxied",
+        free_port(),
+        "limits:\n  trust_forwarded_for: true\n  fetch_per_ip:\n    rate: 0.01\n    burst: 1\n",
+    );
+    let url = &instance.url;
+    wait_for(&format!("{}/healthz", url), |_| true).await;
+
+    // the client cannot escape the limit by prepending addresses
+    assert_eq!(
+        fetch_status_forwarded(url, "10.0.0.1, 192.0.2.1").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        fetch_status_forwarded(url, "10.0.0.2, 192.0.2.1").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(
+        fetch_status_forwarded(url, "192.0.2.1, 192.0.2.2").await,
+        StatusCode::OK
+    );
+}
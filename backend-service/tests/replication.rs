@@ -0,0 +1,90 @@
+use hyper::StatusCode;
+
+mod common;
+
+use common::{
+    free_port, key_count, request, request_with_token, submit_keys, wait_for, wait_for_with_token,
+    Instance,
+};
+
+const PRIMARY_CONFIG: &str =
+    "admin_token: admin\nreplication:\n  token: secret\n  retry_interval: 1\n";
+
+fn replica_config(primary: &str) -> String {
+    format!("{}  primary: {}\n", PRIMARY_CONFIG, primary)
+}
+
+#[tokio::test]
+async fn test_primary_replica() {
+    let dir = tempfile::tempdir().unwrap();
+    let primary = Instance::start(dir.path(), "primary", free_port(), PRIMARY_CONFIG);
+    let replica_port = free_port();
+    let replica = Instance::start(
+        dir.path(),
+        "replica",
+        replica_port,
+        &replica_config(&primary.url),
+    );
+
+    let status_url = format!("{}/replication/status", primary.url);
+    wait_for_with_token(&status_url, Some("admin"), |status| {
+        status["role"] == "primary"
+    })
+    .await;
+    let (status, _) = request("GET", &status_url, None).await.unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(submit_keys(&primary.url, 20).await, StatusCode::OK);
+
+    // the replica serves the same keys under the same cursors
+    let fetch_url = format!("{}/fetch?since=0", replica.url);
+    let replicated = wait_for(&fetch_url, |page| key_count(page) == 20).await;
+    let (_, original) = request("GET", &format!("{}/fetch?since=0", primary.url), None)
+        .await
+        .unwrap();
+    assert_eq!(replicated, original);
+
+    let status_url = format!("{}/replication/status", replica.url);
+    let status = wait_for_with_token(&status_url, Some("admin"), |status| {
+        status["lag_seconds"].as_f64() < Some(5.0)
+    })
+    .await;
+    assert_eq!(status["role"], "replica");
+    assert_eq!(status["connected"], true);
+    assert_eq!(status["cursor"], original["next_cursor"]);
+
+    // replicas are read-only and the stream requires the token
+    assert_eq!(submit_keys(&replica.url, 1).await, StatusCode::FORBIDDEN);
+    // regions are not replicated and must not look empty
+    let (status, _) = request("GET", &format!("{}/fetch/CH?since=0", replica.url), None)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let stream_url = format!("{}/replication/stream?since=0", primary.url);
+    let (status, _) = request("GET", &stream_url, None).await.unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = request_with_token("GET", &stream_url, Some("admin"), None)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // a restarted replica resumes where it stopped
+    drop(replica);
+    assert_eq!(submit_keys(&primary.url, 5).await, StatusCode::OK);
+    let replica = Instance::start(
+        dir.path(),
+        "replica",
+        replica_port,
+        &replica_config(&primary.url),
+    );
+    let fetch_url = format!("{}/fetch?since=0", replica.url);
+    let replicated = wait_for(&fetch_url, |page| key_count(page) == 25).await;
+    let (_, original) = request("GET", &format!("{}/fetch?since=0", primary.url), None)
+        .await
+        .unwrap();
+    assert_eq!(replicated, original);
+    let status = wait_for_with_token(&status_url, Some("admin"), |status| {
+        status["connected"] == true
+    })
+    .await;
+    assert_eq!(status["applied"], 5);
+}
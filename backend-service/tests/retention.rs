@@ -0,0 +1,114 @@
+use backend_service::config::RetentionConfig;
+use backend_service::retention::enforce_retention;
+use backend_service::store::{FileKeyStore, KeyStore, MemoryKeyStore, SqliteKeyStore};
+use contact_tracing::{DailyTracingKey, TracingKey};
+
+/// Adds keys like a snapshot restore as expired days cannot be submitted.
+fn add_keys<S: KeyStore>(store: &S, days: &[u32]) -> Vec<(u32, DailyTracingKey)> {
+    days.iter()
+        .map(|&day| {
+            let key = DailyTracingKey::for_day(&TracingKey::unique(), day);
+            let seq = store
+                .dump()
+                .unwrap()
+                .last()
+                .map_or(1, |&(seq, _, _)| seq + 1);
+            store.import_key(seq, day, key).unwrap();
+            (day, key)
+        })
+        .collect()
+}
+
+#[test]
+fn test_purge_expired_days() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let keys = add_keys(&store, &[today - 30, today - 22, today - 21, today]);
+
+    // load the expired buckets into memory
+    assert_eq!(store.fetch_range(today - 30..=today).unwrap(), keys);
+
+    let removed = enforce_retention(&store, &RetentionConfig::default()).unwrap();
+    assert_eq!(
+        removed.iter().map(|x| (x.day, x.count)).collect::<Vec<_>>(),
+        vec![(today - 30, 1), (today - 22, 1)]
+    );
+    assert!(!dir.path().join(format!("_{}.bucket", today - 30)).exists());
+    assert!(dir.path().join(format!("_{}.bucket", today - 21)).exists());
+    assert_eq!(store.fetch_range(today - 30..=today).unwrap(), keys[2..]);
+
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(store.fetch_range(today - 30..=today).unwrap(), keys[2..]);
+}
+
+#[test]
+fn test_archive_expired_days() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive_dir = tempfile::tempdir().unwrap();
+    let config = RetentionConfig {
+        archive_path: Some(archive_dir.path().to_path_buf()),
+        ..Default::default()
+    };
+
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let keys = add_keys(&store, &[today - 30, today]);
+    assert_eq!(enforce_retention(&store, &config).unwrap().len(), 1);
+    assert_eq!(store.fetch_range(today - 30..=today).unwrap(), keys[1..]);
+
+    let memory_store = MemoryKeyStore::new();
+    let memory_keys = add_keys(&memory_store, &[today - 40, today]);
+    assert_eq!(enforce_retention(&memory_store, &config).unwrap().len(), 1);
+    assert_eq!(
+        memory_store.fetch_range(today - 40..=today).unwrap(),
+        memory_keys[1..]
+    );
+
+    let sqlite_dir = tempfile::tempdir().unwrap();
+    let sqlite_store = SqliteKeyStore::open(sqlite_dir.path().join("keys.sqlite")).unwrap();
+    let sqlite_keys = add_keys(&sqlite_store, &[today - 35, today]);
+    assert_eq!(enforce_retention(&sqlite_store, &config).unwrap().len(), 1);
+    assert_eq!(
+        sqlite_store.fetch_range(today - 40..=today).unwrap(),
+        sqlite_keys[1..]
+    );
+
+    let archive = FileKeyStore::open(archive_dir.path()).unwrap();
+    assert_eq!(
+        archive.fetch_range(today - 40..=today).unwrap(),
+        vec![memory_keys[0], sqlite_keys[0], keys[0]]
+    );
+}
+
+#[test]
+fn test_custom_retention_window() {
+    let config = RetentionConfig {
+        days: 7,
+        ..Default::default()
+    };
+    let store = MemoryKeyStore::new().with_retention_days(config.days);
+    let today = store.current_day();
+    assert_eq!(store.retained_days(), today - 7..=today);
+
+    let keys = add_keys(&store, &[today - 10, today - 7, today]);
+    assert!(!store.has_key(keys[0].1).unwrap());
+    assert!(store.has_key(keys[1].1).unwrap());
+    assert_eq!(
+        store
+            .list_days()
+            .unwrap()
+            .iter()
+            .map(|x| x.day)
+            .collect::<Vec<_>>(),
+        vec![today - 7, today]
+    );
+
+    let removed = enforce_retention(&store, &config).unwrap();
+    assert_eq!(
+        removed.iter().map(|x| x.day).collect::<Vec<_>>(),
+        vec![today - 10]
+    );
+    assert_eq!(store.fetch_range(today - 10..=today).unwrap(), keys[1..]);
+}
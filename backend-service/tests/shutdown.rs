@@ -0,0 +1,78 @@
+use std::fs;
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+mod common;
+
+use common::{free_port, key_count, request, submit_keys, wait_for, wait_for_with_token, Instance};
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let mut instance = Instance::start(dir.path(), "backend", port, "");
+    let fetch_url = format!("{}/fetch?since=0", instance.url);
+    wait_for(&fetch_url, |_| true).await;
+    assert_eq!(submit_keys(&instance.url, 10).await, StatusCode::OK);
+
+    instance.signal("TERM");
+    assert!(instance.wait().success());
+    assert!(request("GET", &fetch_url, None).await.is_none());
+
+    // the store was released and has all keys
+    let instance = Instance::start(dir.path(), "backend", port, "");
+    let page = wait_for(&fetch_url, |_| true).await;
+    assert_eq!(key_count(&page), 10);
+    drop(instance);
+}
+
+#[tokio::test]
+async fn test_shutdown_with_replica() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = "admin_token: admin\nreplication:\n  token: secret\n  retry_interval: 1\n";
+    let mut primary = Instance::start(dir.path(), "primary", free_port(), config);
+    let replica = Instance::start(
+        dir.path(),
+        "replica",
+        free_port(),
+        &format!("{}  primary: {}\n", config, primary.url),
+    );
+    let status_url = format!("{}/replication/status", replica.url);
+    wait_for_with_token(&status_url, Some("admin"), |status| {
+        status["connected"] == true
+    })
+    .await;
+
+    // the open replication stream does not keep the primary alive
+    primary.signal("TERM");
+    assert!(primary.wait_timeout(Duration::from_secs(10)).success());
+    drop(replica);
+}
+
+#[tokio::test]
+async fn test_reload_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let instance = Instance::start(dir.path(), "backend", port, "fetch_page_size: 2\n");
+    let fetch_url = format!("{}/fetch?since=0", instance.url);
+    wait_for(&fetch_url, |_| true).await;
+    assert_eq!(submit_keys(&instance.url, 5).await, StatusCode::OK);
+    let (_, page) = request("GET", &fetch_url, None).await.unwrap();
+    assert_eq!(key_count(&page), 2);
+
+    Instance::write_config(dir.path(), "backend", port, "fetch_page_size: 10\n");
+    instance.signal("HUP");
+    wait_for(&fetch_url, |page| key_count(page) == 5).await;
+
+    // a broken config is ignored and later reloads still work
+    let config_path = Instance::write_config(dir.path(), "backend", port, "");
+    fs::write(&config_path, "fetch_page_size: [").unwrap();
+    instance.signal("HUP");
+    let (status, page) = request("GET", &fetch_url, None).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(key_count(&page), 5);
+    Instance::write_config(dir.path(), "backend", port, "fetch_page_size: 3\n");
+    instance.signal("HUP");
+    wait_for(&fetch_url, |page| key_count(page) == 3).await;
+}
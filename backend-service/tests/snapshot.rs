@@ -0,0 +1,257 @@
+mod common;
+
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::process::Command;
+
+use backend_service::federation::{load_tags, KeyTags, TagRecord};
+use backend_service::snapshot::Snapshot;
+use backend_service::store::{
+    FileKeyStore, KeyStore, MemoryKeyStore, Partitions, Region, SqliteKeyStore,
+};
+use contact_tracing::{DailyTracingKey, TracingKey};
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use common::{free_port, submit_keys, wait_for, Instance};
+
+fn fill_store<S: KeyStore>(store: &S) -> Vec<(u64, u32, DailyTracingKey)> {
+    let today = store.current_day();
+    let mut seq = 0;
+    for offset in 0..30 {
+        let day = today - offset;
+        for _ in 0..3 {
+            // days outside of the retention window cannot be submitted
+            let key = DailyTracingKey::for_day(&TracingKey::unique(), day);
+            seq += 1;
+            assert!(store.import_key(seq, day, key).unwrap());
+        }
+    }
+    store.dump().unwrap()
+}
+
+/// Replaces a member of a snapshot zip.
+fn replace_member(data: &[u8], name: &str, mut f: impl FnMut(&mut Vec<u8>)) -> Vec<u8> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for idx in 0..archive.len() {
+        let mut member = archive.by_index(idx).unwrap();
+        let mut contents = vec![];
+        member.read_to_end(&mut contents).unwrap();
+        if member.name() == name {
+            f(&mut contents);
+        }
+        zip.start_file(member.name(), FileOptions::default())
+            .unwrap();
+        zip.write_all(&contents).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[test]
+fn test_snapshot_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path().join("file")).unwrap();
+    let entries = fill_store(&store);
+    // days outside of the retention window are part of the snapshot too
+    assert_eq!(entries.len(), 90);
+    store.compact().unwrap();
+
+    let snapshot = Snapshot::capture(&store).unwrap();
+    assert_eq!(snapshot.entries, entries);
+    let manifest = snapshot.manifest();
+    assert_eq!(manifest.count, 90);
+    assert_eq!(manifest.days.len(), 30);
+    assert_eq!(manifest.max_seq, entries.last().unwrap().0);
+
+    let parsed = Snapshot::from_zip(&snapshot.to_zip().unwrap()).unwrap();
+    assert_eq!(parsed, snapshot);
+
+    // restoring into another backend keeps keys and sequence numbers
+    let target = SqliteKeyStore::open(dir.path().join("keys.sqlite")).unwrap();
+    assert_eq!(parsed.restore(&target).unwrap(), 90);
+    assert_eq!(target.dump().unwrap(), entries);
+    assert_eq!(
+        target.fetch_since(0, usize::MAX).unwrap().keys,
+        store.fetch_since(0, usize::MAX).unwrap().keys
+    );
+    for day in store.retained_days() {
+        assert_eq!(
+            target.day_summary(day).unwrap().map(|x| x.digest),
+            store.day_summary(day).unwrap().map(|x| x.digest)
+        );
+    }
+
+    // new keys continue after the restored sequence numbers
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), target.current_day());
+    assert!(target.add_key(target.current_day(), key).unwrap());
+    assert!(target.dump().unwrap().last().unwrap().0 > manifest.max_seq);
+
+    // and back into a file store
+    let restored = FileKeyStore::open(dir.path().join("restored")).unwrap();
+    Snapshot::capture(&target)
+        .unwrap()
+        .restore(&restored)
+        .unwrap();
+    drop(restored);
+    let restored = FileKeyStore::open(dir.path().join("restored")).unwrap();
+    assert_eq!(restored.dump().unwrap(), target.dump().unwrap());
+    assert!(restored.has_key(key).unwrap());
+}
+
+#[test]
+fn test_snapshot_checksum() {
+    let store = MemoryKeyStore::new();
+    fill_store(&store);
+    let data = Snapshot::capture(&store).unwrap().to_zip().unwrap();
+
+    let corrupted = replace_member(&data, "keys.bin", |keys| keys[30] ^= 1);
+    let err = Snapshot::from_zip(&corrupted).unwrap_err();
+    assert_eq!(err.to_string(), "bad checksum, corrupted snapshot");
+
+    let truncated = replace_member(&data, "keys.bin", |keys| keys.truncate(28));
+    assert!(Snapshot::from_zip(&truncated).is_err());
+
+    let tampered = replace_member(&data, "manifest.json", |manifest| {
+        let text = String::from_utf8(manifest.clone()).unwrap();
+        *manifest = text
+            .replacen("\"count\": 90", "\"count\": 91", 1)
+            .into_bytes();
+    });
+    assert!(Snapshot::from_zip(&tampered).is_err());
+}
+
+#[test]
+fn test_restore_into_non_empty_store() {
+    let store = MemoryKeyStore::new();
+    fill_store(&store);
+    let snapshot = Snapshot::capture(&store).unwrap();
+    assert!(snapshot.restore(&store).is_err());
+
+    // sequence numbers can never be reused
+    let (seq, day, _) = snapshot.entries[0];
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), day);
+    assert!(store.import_key(seq, day, key).is_err());
+}
+
+#[test]
+fn test_snapshot_tags() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = MemoryKeyStore::new();
+    let entries = fill_store(&store);
+    let mut snapshot = Snapshot::capture(&store).unwrap();
+    snapshot.tags = entries[..2]
+        .iter()
+        .map(|&(_, day, key)| TagRecord {
+            day,
+            key,
+            tags: KeyTags {
+                origin: "DE".into(),
+                visited: vec!["CH".into()],
+            },
+        })
+        .collect();
+    // the tags are read back in day and key order
+    snapshot
+        .tags
+        .sort_by_key(|record| record.key.as_bytes().to_vec());
+    let data = snapshot.to_zip().unwrap();
+    let parsed = Snapshot::from_zip(&data).unwrap();
+    assert_eq!(parsed, snapshot);
+    assert_eq!(parsed.manifest().tag_count, 2);
+
+    let corrupted = replace_member(&data, "tags.jsonl", |tags| tags[10] ^= 1);
+    let err = Snapshot::from_zip(&corrupted).unwrap_err();
+    assert_eq!(err.to_string(), "bad checksum, corrupted snapshot");
+
+    let state_path = dir.path().join("federation");
+    let target = MemoryKeyStore::new();
+    assert_eq!(parsed.restore_federated(&target, &state_path).unwrap(), 90);
+    assert_eq!(load_tags(&state_path).unwrap(), snapshot.tags);
+}
+
+fn memory_partitions() -> Partitions {
+    Partitions::new(|_| Ok(Box::new(MemoryKeyStore::new()) as Box<dyn KeyStore>))
+}
+
+#[test]
+fn test_snapshot_partitions() {
+    let store = MemoryKeyStore::new();
+    fill_store(&store);
+    let partitions = memory_partitions();
+    let regions: Vec<Region> = vec!["CH".parse().unwrap(), "DE".parse().unwrap()];
+    for region in &regions {
+        fill_store(&**partitions.open(region).unwrap().inner());
+    }
+
+    let mut snapshot = Snapshot::capture(&store).unwrap();
+    snapshot.capture_partitions(&partitions).unwrap();
+    let manifest = snapshot.manifest();
+    assert_eq!(manifest.partitions.len(), 2);
+    assert_eq!(manifest.partitions[&regions[0]].count, 90);
+    let data = snapshot.to_zip().unwrap();
+    let parsed = Snapshot::from_zip(&data).unwrap();
+    assert_eq!(parsed, snapshot);
+
+    let corrupted = replace_member(&data, "regions/DE.bin", |keys| keys[30] ^= 1);
+    let err = Snapshot::from_zip(&corrupted).unwrap_err();
+    assert_eq!(err.to_string(), "bad checksum, corrupted snapshot");
+
+    let target = memory_partitions();
+    assert_eq!(parsed.restore_partitions(&target).unwrap(), 180);
+    assert_eq!(target.regions(), regions);
+    for region in &regions {
+        assert_eq!(
+            target.get(region).inner().dump().unwrap(),
+            partitions.get(region).inner().dump().unwrap()
+        );
+    }
+    // partitions are only restored when they are all empty
+    assert!(parsed.restore_partitions(&target).is_err());
+}
+
+#[tokio::test]
+async fn test_snapshot_running_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let instance = Instance::start(dir.path(), "backend", free_port(), "admin_token: admin\n");
+    wait_for(&format!("{}/readyz", instance.url), |_| true).await;
+    submit_keys(&instance.url, 5).await;
+
+    let config_path = dir.path().join("backend.yml");
+    let path = dir.path().join("keys.snapshot");
+    let snapshot = |server: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_backend-service"));
+        command
+            .arg("--config")
+            .arg(&config_path)
+            .arg("snapshot")
+            .arg(&path);
+        if let Some(server) = server {
+            command.arg("--server").arg(server);
+        }
+        command.status().unwrap()
+    };
+
+    // the store is locked by the server
+    assert!(!snapshot(None).success());
+    assert!(!path.exists());
+
+    assert!(snapshot(Some(&instance.url)).success());
+    let taken = Snapshot::from_zip(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(taken.entries.len(), 5);
+    assert_eq!(
+        taken.entries.iter().map(|x| x.0).collect::<Vec<_>>(),
+        vec![1, 2, 3, 4, 5]
+    );
+
+    // without the server the store can be snapshotted directly
+    drop(instance);
+    fs::remove_file(&path).unwrap();
+    assert!(snapshot(None).success());
+    assert_eq!(
+        Snapshot::from_zip(&fs::read(&path).unwrap())
+            .unwrap()
+            .entries,
+        taken.entries
+    );
+}
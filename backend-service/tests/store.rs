@@ -0,0 +1,642 @@
+use std::fs;
+use std::io;
+
+use bytes::BufMut;
+use crc::crc32;
+
+use backend_service::store::{
+    AsyncKeyStore, BucketReport, DayInfo, DedupIndex, FileKeyStore, FileStoreOptions, KeyStore,
+    MemoryKeyStore, SqliteKeyStore,
+};
+use contact_tracing::{DailyTracingKey, TracingKey};
+
+#[test]
+fn test_fetch_since_pagination() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+
+    let keys: Vec<_> = (0..5)
+        .map(|_| DailyTracingKey::for_day(&TracingKey::unique(), today))
+        .collect();
+    for key in &keys {
+        assert!(store.add_key(today, *key).unwrap());
+    }
+
+    let page = store.fetch_since(0, 3).unwrap();
+    assert!(page.has_more);
+    assert_eq!(
+        page.keys,
+        keys[..3].iter().map(|k| (today, *k)).collect::<Vec<_>>()
+    );
+
+    let page = store.fetch_since(page.next_cursor, 3).unwrap();
+    assert!(!page.has_more);
+    assert_eq!(
+        page.keys,
+        keys[3..].iter().map(|k| (today, *k)).collect::<Vec<_>>()
+    );
+
+    let empty = store.fetch_since(page.next_cursor, 3).unwrap();
+    assert!(empty.keys.is_empty());
+    assert_eq!(empty.next_cursor, page.next_cursor);
+
+    // sequence numbers continue after reopening
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    store.add_key(today, key).unwrap();
+    let page = store.fetch_since(page.next_cursor, 3).unwrap();
+    assert_eq!(page.keys, vec![(today, key)]);
+}
+
+#[test]
+fn test_legacy_bucket_upgrade() {
+    let dir = tempfile::tempdir().unwrap();
+    let today = FileKeyStore::open(dir.path()).unwrap().current_day();
+
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    let mut legacy = vec![];
+    legacy.put_slice(key.as_bytes());
+    legacy.put_u32_le(crc32::checksum_ieee(key.as_bytes()));
+    fs::write(dir.path().join(format!("_{}.bucket", today)), legacy).unwrap();
+
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let page = store.fetch_since(0, 10).unwrap();
+    assert_eq!(page.keys, vec![(today, key)]);
+    assert_eq!(page.next_cursor, 2);
+}
+
+#[test]
+fn test_cache_info_tracks_contents() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+
+    let before = store.cache_info(today - 1..=today).unwrap();
+    assert!(!before.closed);
+    assert!(before.last_modified.is_none());
+    assert_eq!(
+        before.digest,
+        store.cache_info(today - 1..=today).unwrap().digest
+    );
+
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today - 1);
+    store.add_key(today - 1, key).unwrap();
+    let after = store.cache_info(today - 1..=today).unwrap();
+    assert_ne!(before.digest, after.digest);
+    assert!(after.last_modified.is_some());
+
+    let closed = store.cache_info(today - 1..=today - 1).unwrap();
+    assert!(closed.closed);
+}
+
+#[test]
+fn test_days() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+
+    let tkey = TracingKey::unique();
+    for day in &[today - 2, today - 2, today] {
+        let key = DailyTracingKey::for_day(&TracingKey::unique(), *day);
+        store.add_key(*day, key).unwrap();
+    }
+    let key = DailyTracingKey::for_day(&tkey, today - 1);
+    store.add_key(today - 1, key).unwrap();
+
+    let days = store.list_days().unwrap();
+    assert_eq!(
+        days.iter().map(|x| (x.day, x.count)).collect::<Vec<_>>(),
+        vec![(today - 2, 2), (today - 1, 1), (today, 1)]
+    );
+
+    let day_keys = store.fetch_day(today - 1).unwrap().unwrap();
+    assert_eq!(day_keys.day, today - 1);
+    assert_eq!(day_keys.keys, vec![key]);
+
+    assert!(store.fetch_day(today + 1).unwrap().is_none());
+    assert!(store.fetch_day(today - 100).unwrap().is_none());
+}
+
+fn check_store<S: KeyStore>(store: &S) {
+    let today = store.current_day();
+    assert!(store.list_days().unwrap().is_empty());
+    let empty = store.cache_info(today - 2..=today).unwrap();
+
+    let keys: Vec<_> = [today - 30, today - 2, today - 2, today]
+        .iter()
+        .map(|&day| (day, DailyTracingKey::for_day(&TracingKey::unique(), day)))
+        .collect();
+    // expired days can only be imported
+    assert!(store.import_key(1, keys[0].0, keys[0].1).unwrap());
+    for &(day, key) in &keys[1..] {
+        assert!(store.add_key(day, key).unwrap());
+    }
+    assert!(!store.add_key(today, keys[1].1).unwrap());
+    assert!(store.has_key(keys[1].1).unwrap());
+    assert!(store.has_key(keys[3].1).unwrap());
+    assert!(!store
+        .has_key(DailyTracingKey::for_day(&TracingKey::unique(), today))
+        .unwrap());
+
+    assert_eq!(store.fetch_range(today - 2..=today).unwrap(), keys[1..]);
+    assert_eq!(
+        store.list_days().unwrap(),
+        vec![
+            DayInfo {
+                day: today - 2,
+                count: 2
+            },
+            DayInfo {
+                day: today,
+                count: 1
+            },
+        ]
+    );
+    assert_eq!(
+        store.fetch_day(today).unwrap().unwrap().keys,
+        vec![keys[3].1]
+    );
+    assert_ne!(
+        store.cache_info(today - 2..=today).unwrap().digest,
+        empty.digest
+    );
+
+    let page = store.fetch_since(0, 2).unwrap();
+    assert!(page.has_more);
+    assert_eq!(page.keys, keys[1..3]);
+    let page = store.fetch_since(page.next_cursor, 2).unwrap();
+    assert!(!page.has_more);
+    assert_eq!(page.keys, keys[3..]);
+
+    let removed = store.purge(today - 1).unwrap();
+    assert_eq!(
+        removed.iter().map(|x| (x.day, x.count)).collect::<Vec<_>>(),
+        vec![(today - 30, 1), (today - 2, 2)]
+    );
+    assert_eq!(store.fetch_range(today - 30..=today).unwrap(), keys[3..]);
+    assert!(store.purge(today - 1).unwrap().is_empty());
+}
+
+#[test]
+fn test_file_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    check_store(&FileKeyStore::open(dir.path()).unwrap());
+}
+
+#[test]
+fn test_memory_backend() {
+    check_store(&MemoryKeyStore::new());
+}
+
+#[test]
+fn test_sqlite_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    check_store(&SqliteKeyStore::open(dir.path().join("keys.sqlite")).unwrap());
+}
+
+#[test]
+fn test_torn_record_recovery() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let keys: Vec<_> = (0..2)
+        .map(|_| DailyTracingKey::for_day(&TracingKey::unique(), today))
+        .collect();
+    for key in &keys {
+        store.add_key(today, *key).unwrap();
+    }
+    drop(store);
+
+    // simulate a crash in the middle of appending a record
+    let path = dir.path().join(format!("_{}.bucket", today));
+    let len = fs::metadata(&path).unwrap().len();
+    let mut data = fs::read(&path).unwrap();
+    data.extend_from_slice(&[0xff; 11]);
+    fs::write(&path, data).unwrap();
+
+    // and in the middle of writing the header of a new bucket
+    let torn_header_path = dir.path().join(format!("_{}.bucket", today - 1));
+    fs::write(&torn_header_path, b"DTKB").unwrap();
+
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    assert_eq!(fs::metadata(&torn_header_path).unwrap().len(), 0);
+
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    store.add_key(today, key).unwrap();
+    let page = store.fetch_since(0, 10).unwrap();
+    assert_eq!(
+        page.keys,
+        vec![(today, keys[0]), (today, keys[1]), (today, key)]
+    );
+    assert_eq!(page.next_cursor, 4);
+}
+
+#[test]
+fn test_corrupted_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let keys: Vec<_> = (0..3)
+        .map(|_| DailyTracingKey::for_day(&TracingKey::unique(), today))
+        .collect();
+    for key in &keys {
+        store.add_key(today, *key).unwrap();
+    }
+    drop(store);
+
+    // flip a bit in the key of the last record
+    let path = dir.path().join(format!("_{}.bucket", today));
+    let mut data = fs::read(&path).unwrap();
+    let idx = data.len() - 10;
+    data[idx] ^= 1;
+    fs::write(&path, data).unwrap();
+
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(
+        store.check().unwrap(),
+        vec![BucketReport {
+            day: today,
+            keys: 2,
+            corrupted: 1,
+        }]
+    );
+    assert_eq!(
+        store.fetch_range(today..=today).unwrap(),
+        vec![(today, keys[0]), (today, keys[1])]
+    );
+
+    // sequence numbers continue after the last intact record
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    store.add_key(today, key).unwrap();
+    assert_eq!(store.fetch_since(3, 10).unwrap().keys, vec![(today, key)]);
+}
+
+#[test]
+fn test_corrupted_bucket_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    store.add_key(today, key).unwrap();
+    store
+        .add_key(
+            today - 1,
+            DailyTracingKey::for_day(&TracingKey::unique(), today),
+        )
+        .unwrap();
+
+    let options = FileStoreOptions {
+        read_only: true,
+        ..FileStoreOptions::default()
+    };
+    let reader = FileKeyStore::open_with_options(dir.path(), options).unwrap();
+
+    // a header that is still being written is an empty bucket
+    fs::write(dir.path().join(format!("_{}.bucket", today - 2)), b"DTK").unwrap();
+    // an unknown header skips the bucket but serves all other days
+    let broken_path = dir.path().join(format!("_{}.bucket", today - 1));
+    let mut data = fs::read(&broken_path).unwrap();
+    data[0] ^= 1;
+    fs::write(&broken_path, data).unwrap();
+
+    assert_eq!(
+        reader.fetch_range(today - 2..=today).unwrap(),
+        vec![(today, key)]
+    );
+    assert_eq!(reader.list_days().unwrap().len(), 1);
+    assert_eq!(reader.corrupt_days(), vec![today - 1]);
+    assert_eq!(
+        reader.check().unwrap(),
+        vec![
+            BucketReport {
+                day: today - 2,
+                keys: 0,
+                corrupted: 0,
+            },
+            BucketReport {
+                day: today - 1,
+                keys: 0,
+                corrupted: 1,
+            },
+            BucketReport {
+                day: today,
+                keys: 1,
+                corrupted: 0,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_purge_corrupt_buckets() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive_dir = tempfile::tempdir().unwrap();
+    let today = FileKeyStore::open(dir.path()).unwrap().current_day();
+    fs::write(
+        dir.path().join(format!("_{}.segment", today - 3)),
+        b"broken",
+    )
+    .unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(store.corrupt_days(), vec![today - 3]);
+    let corrupt_path = |day: u32| dir.path().join(format!("_{}.bucket.corrupt", day));
+    fs::write(corrupt_path(today - 2), b"broken").unwrap();
+    fs::write(corrupt_path(today - 1), b"broken").unwrap();
+
+    // buckets that were set aside are archived or purged with their day
+    store.archive(today - 1, archive_dir.path()).unwrap();
+    assert!(!corrupt_path(today - 2).exists());
+    assert!(archive_dir
+        .path()
+        .join(format!("_{}.bucket.corrupt", today - 2))
+        .exists());
+    assert!(corrupt_path(today - 1).exists());
+    store.purge(today).unwrap();
+    assert!(!corrupt_path(today - 1).exists());
+    assert!(store.corrupt_days().is_empty());
+}
+
+#[test]
+fn test_dedup_window() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let edge_key = DailyTracingKey::for_day(&TracingKey::unique(), today - 21);
+    let expired_key = DailyTracingKey::for_day(&TracingKey::unique(), today - 22);
+    let today_key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    assert!(store.add_key(today - 21, edge_key).unwrap());
+    assert!(store.add_key(today, today_key).unwrap());
+
+    // days outside of the window are rejected instead of being stored on
+    // every resubmission
+    let future_key = DailyTracingKey::for_day(&TracingKey::unique(), today + 1);
+    for &(day, key) in &[
+        (today - 22, expired_key),
+        (today + 1, future_key),
+        (today + 1, future_key),
+    ] {
+        let err = store.add_key(day, key).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(store.fetch_range(today - 22..=today + 1).unwrap().len(), 2);
+    assert!(!dir.path().join(format!("_{}.bucket", today + 1)).exists());
+    assert!(!store.has_key(future_key).unwrap());
+
+    // keys of today's bucket are deduplicated too
+    assert!(!store.add_key(today, today_key).unwrap());
+    assert!(!store.add_key(today - 1, today_key).unwrap());
+    assert!(!store.add_key(today, edge_key).unwrap());
+    assert!(!store.has_key(expired_key).unwrap());
+
+    // the index is rebuilt on startup
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert!(store.has_key(today_key).unwrap());
+    assert!(store.has_key(edge_key).unwrap());
+    assert!(!store.has_key(expired_key).unwrap());
+    assert!(!store.add_key(today, today_key).unwrap());
+    assert_eq!(store.fetch_range(today..=today).unwrap().len(), 1);
+}
+
+#[test]
+fn test_dedup_early_days() {
+    // the retention window must not underflow for small day numbers
+    let store = MemoryKeyStore::with_current_day(3);
+    assert_eq!(store.retained_days(), 0..=3);
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), 0);
+    assert!(!store.has_key(key).unwrap());
+    assert!(store.add_key(0, key).unwrap());
+    assert!(!store.add_key(3, key).unwrap());
+    assert_eq!(store.list_days().unwrap().len(), 1);
+}
+
+#[test]
+fn test_dedup_index() {
+    let mut index = DedupIndex::new();
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), 10);
+    assert!(!index.contains(&key, &(0..=30)));
+    index.insert(key, 10);
+    assert!(index.contains(&key, &(10..=10)));
+    assert!(index.contains(&key, &(0..=30)));
+    assert!(!index.contains(&key, &(11..=30)));
+    index.evict_before(10);
+    assert_eq!(index.len(), 1);
+    index.evict_before(11);
+    assert!(index.is_empty());
+}
+
+#[tokio::test]
+async fn test_async_store() {
+    let store = AsyncKeyStore::new(MemoryKeyStore::new());
+    let today = store.inner().current_day();
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    assert_eq!(
+        store
+            .add_keys(vec![(today, key), (today, key)])
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        store.fetch_range(today..=today).await.unwrap(),
+        vec![(today, key)]
+    );
+    assert_eq!(store.list_days().await.unwrap().len(), 1);
+    assert_eq!(store.fetch_since(0, 10).await.unwrap().keys.len(), 1);
+    assert_eq!(
+        store.fetch_day(today).await.unwrap().unwrap().keys,
+        vec![key]
+    );
+}
+
+#[test]
+fn test_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let bucket_path = |day: u32| dir.path().join(format!("_{}.bucket", day));
+    let segment_path = |day: u32| dir.path().join(format!("_{}.segment", day));
+
+    for &day in &[today - 2, today - 1, today] {
+        for _ in 0..100 {
+            let key = DailyTracingKey::for_day(&TracingKey::unique(), day);
+            store.add_key(day, key).unwrap();
+        }
+    }
+    let keys = store.fetch_range(today - 2..=today).unwrap();
+    let page = store.fetch_since(0, 1000).unwrap();
+    let digest = store.cache_info(today - 2..=today).unwrap().digest;
+    let bucket_size = fs::metadata(bucket_path(today - 2)).unwrap().len();
+
+    let compacted = store.compact().unwrap();
+    assert_eq!(
+        compacted
+            .iter()
+            .map(|x| (x.day, x.count))
+            .collect::<Vec<_>>(),
+        vec![(today - 2, 100), (today - 1, 100)]
+    );
+    assert!(!bucket_path(today - 2).exists());
+    assert!(bucket_path(today).exists());
+    assert!(fs::metadata(segment_path(today - 2)).unwrap().len() < bucket_size);
+    assert_eq!(store.cache_info(today - 2..=today).unwrap().digest, digest);
+
+    // both formats are loaded transparently after a restart
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(store.fetch_range(today - 2..=today).unwrap(), keys);
+    assert_eq!(store.fetch_since(0, 1000).unwrap().keys, page.keys);
+    assert_eq!(store.cache_info(today - 2..=today).unwrap().digest, digest);
+    assert!(!store.add_key(today, keys[0].1).unwrap());
+
+    // late keys for a compacted day are merged on the next compaction
+    let late_key = DailyTracingKey::for_day(&TracingKey::unique(), today - 2);
+    store.add_key(today - 2, late_key).unwrap();
+    let page = store.fetch_since(page.next_cursor, 10).unwrap();
+    assert_eq!(page.keys, vec![(today - 2, late_key)]);
+    assert!(bucket_path(today - 2).exists());
+    assert_eq!(store.compact().unwrap().len(), 1);
+    assert!(!bucket_path(today - 2).exists());
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(store.fetch_day(today - 2).unwrap().unwrap().keys.len(), 101);
+
+    // a corrupted segment is reported but does not break other days
+    let mut data = fs::read(segment_path(today - 1)).unwrap();
+    let idx = data.len() - 5;
+    data[idx] ^= 0xff;
+    fs::write(segment_path(today - 1), data).unwrap();
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let report = store.check().unwrap();
+    assert_eq!(report[1].day, today - 1);
+    assert_eq!(report[1].corrupted, 100);
+    assert_eq!(store.fetch_range(today - 2..=today).unwrap().len(), 201);
+
+    assert_eq!(store.purge(today).unwrap().len(), 2);
+    assert!(!segment_path(today - 2).exists());
+    assert_eq!(store.fetch_range(today - 2..=today).unwrap().len(), 100);
+}
+
+#[test]
+fn test_corrupted_segment_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let segment_path = dir.path().join(format!("_{}.segment", today - 1));
+    for &day in &[today - 1, today] {
+        let key = DailyTracingKey::for_day(&TracingKey::unique(), day);
+        store.add_key(day, key).unwrap();
+    }
+    store.compact().unwrap();
+    drop(store);
+
+    // a huge key count and sequence number in the header
+    let mut data = fs::read(&segment_path).unwrap();
+    data[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+    data[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+    fs::write(&segment_path, data).unwrap();
+
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(store.fetch_range(today - 1..=today).unwrap().len(), 1);
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    store.add_key(today, key).unwrap();
+    assert_eq!(store.fetch_since(3, 10).unwrap().keys, vec![(today, key)]);
+
+    // a header that is intact apart from the sequence number
+    let mut data = fs::read(&segment_path).unwrap();
+    data[12..16].copy_from_slice(&1u32.to_le_bytes());
+    fs::write(&segment_path, data).unwrap();
+    drop(store);
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    assert_eq!(store.fetch_range(today - 1..=today).unwrap().len(), 2);
+}
+
+#[test]
+fn test_seq_watermark() {
+    let dir = tempfile::tempdir().unwrap();
+    let stores: Vec<Box<dyn KeyStore>> = vec![
+        Box::new(MemoryKeyStore::new()),
+        Box::new(FileKeyStore::open(dir.path()).unwrap()),
+    ];
+    for store in &stores {
+        let today = store.current_day();
+        assert_eq!(store.seq_watermark(), Some(1));
+        let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+        store.add_key(today, key).unwrap();
+        assert_eq!(store.seq_watermark(), Some(2));
+        store.add_key(today, key).unwrap();
+        assert_eq!(store.seq_watermark(), Some(2));
+    }
+
+    // readers cannot see the writes of other processes
+    let options = FileStoreOptions {
+        read_only: true,
+        ..FileStoreOptions::default()
+    };
+    let reader = FileKeyStore::open_with_options(dir.path(), options).unwrap();
+    assert_eq!(reader.seq_watermark(), None);
+}
+
+#[test]
+fn test_store_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileKeyStore::open(dir.path()).unwrap();
+    let today = store.current_day();
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    store.add_key(today, key).unwrap();
+
+    let err = FileKeyStore::open(dir.path()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    // read-only stores can be opened next to a writer and see its writes
+    let options = FileStoreOptions {
+        read_only: true,
+        ..FileStoreOptions::default()
+    };
+    let reader = FileKeyStore::open_with_options(dir.path(), options.clone()).unwrap();
+    assert_eq!(
+        reader.fetch_range(today..=today).unwrap(),
+        vec![(today, key)]
+    );
+    let other_key = DailyTracingKey::for_day(&TracingKey::unique(), today - 1);
+    store.add_key(today - 1, other_key).unwrap();
+    assert_eq!(reader.fetch_since(0, 10).unwrap().keys.len(), 2);
+    store.compact().unwrap();
+    assert_eq!(reader.fetch_range(today - 1..=today).unwrap().len(), 2);
+    store.purge(today).unwrap();
+    assert_eq!(reader.fetch_range(today - 1..=today).unwrap().len(), 1);
+
+    let err = reader.add_key(today, key).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(reader.purge(today).is_err());
+    assert!(reader.compact().is_err());
+
+    drop(store);
+    FileKeyStore::open(dir.path()).unwrap();
+
+    // a read-only store does not create missing folders
+    assert!(FileKeyStore::open_with_options(dir.path().join("missing"), options).is_err());
+    assert!(!dir.path().join("missing").exists());
+}
+
+#[test]
+fn test_sqlite_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keys.sqlite");
+    let store = SqliteKeyStore::open(&path).unwrap();
+    let today = store.current_day();
+    let key = DailyTracingKey::for_day(&TracingKey::unique(), today);
+    store.add_key(today, key).unwrap();
+
+    let reader = SqliteKeyStore::open_read_only(&path).unwrap();
+    assert_eq!(
+        reader.fetch_range(today..=today).unwrap(),
+        vec![(today, key)]
+    );
+    let err = reader.add_key(today, key).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use std::collections::HashSet;
+
+use backend_service::store::DAYS_WINDOW;
+use backend_service::submission::{
+    DailyTracingKeyStoreRequest, SubmitResponse, ENVELOPE_SIZE, UPLOAD_DAYS,
+};
+use backend_service::utils::{decode_body, ResponseFormat};
+use contact_tracing::{day_number_for_timestamp, DailyTracingKey, TracingKey};
+use hyper::StatusCode;
+
+use common::{free_port, key_count, random_keys, request, submit_keys, wait_for, Instance};
+
+#[test]
+fn test_fake_request() {
+    let fake = DailyTracingKeyStoreRequest::fake();
+    assert!(fake.fake);
+    assert_eq!(fake.keys.len(), UPLOAD_DAYS as usize);
+    let days: HashSet<_> = fake.keys.iter().map(|&(day, _)| day).collect();
+    assert_eq!(days.len(), UPLOAD_DAYS as usize);
+
+    let other = DailyTracingKeyStoreRequest::fake();
+    assert_ne!(fake.keys, other.keys);
+
+    let real = DailyTracingKeyStoreRequest::new(other.keys);
+    assert!(!real.fake);
+    let json = serde_json::to_value(&real).unwrap();
+    assert_eq!(json["fake"], false);
+}
+
+#[test]
+fn test_padding() {
+    let envelope_len =
+        |request: &DailyTracingKeyStoreRequest| serde_json::to_vec(request).unwrap().len();
+    let fake = DailyTracingKeyStoreRequest::fake();
+    assert_eq!(envelope_len(&fake), ENVELOPE_SIZE);
+    for count in &[0, 1, 5, DAYS_WINDOW] {
+        let mut request = DailyTracingKeyStoreRequest::new(random_keys(*count));
+        request.visited_countries = vec!["DE".into(), "FR".into()];
+        let mut request = request.padded();
+        assert_eq!(envelope_len(&request), ENVELOPE_SIZE);
+        request.strip_padding(ENVELOPE_SIZE).unwrap();
+        assert_eq!(request.padding, "");
+    }
+
+    // binary uploads are padded for their own encoding
+    let formats = [
+        (ResponseFormat::Cbor, "application/cbor"),
+        (ResponseFormat::MessagePack, "application/msgpack"),
+    ];
+    for &(format, content_type) in &formats {
+        for count in 0..=DAYS_WINDOW {
+            let mut request = DailyTracingKeyStoreRequest::new(random_keys(count));
+            request.visited_countries = vec!["DE".into(), "FR".into()];
+            let request = request.padded_for(format).unwrap();
+            let body = match format {
+                ResponseFormat::Cbor => serde_cbor::to_vec(&request).unwrap(),
+                _ => rmp_serde::to_vec_named(&request).unwrap(),
+            };
+            assert_eq!(
+                body.len(),
+                ENVELOPE_SIZE,
+                "{:?} with {} keys",
+                format,
+                count
+            );
+            let mut decoded: DailyTracingKeyStoreRequest =
+                decode_body(Some(content_type), &body).unwrap();
+            decoded.strip_padding(body.len()).unwrap();
+            assert_eq!(decoded.keys, request.keys);
+        }
+    }
+    let request = DailyTracingKeyStoreRequest::new(random_keys(1));
+    assert!(request.padded_for(ResponseFormat::Protobuf).is_err());
+
+    let mut request = DailyTracingKeyStoreRequest::new(random_keys(1));
+    request.padding = "secret".into();
+    assert!(request.strip_padding(ENVELOPE_SIZE).is_err());
+
+    // uploads have to be exactly one envelope
+    let mut request = DailyTracingKeyStoreRequest::new(random_keys(1)).padded();
+    assert!(request.strip_padding(ENVELOPE_SIZE - 1).is_err());
+    assert!(request.strip_padding(ENVELOPE_SIZE + 1).is_err());
+    let mut request = DailyTracingKeyStoreRequest::new(random_keys(1));
+    assert!(request.strip_padding(envelope_len(&request)).is_err());
+}
+
+#[tokio::test]
+async fn test_fake_submissions() {
+    let dir = tempfile::tempdir().unwrap();
+    let instance = Instance::start(dir.path(), "backend", free_port(), "");
+    let fetch_url = format!("{}/fetch?since=0", instance.url);
+    wait_for(&fetch_url, |_| true).await;
+
+    let submit_url = format!("{}/submit", instance.url);
+    let fake = serde_json::to_value(DailyTracingKeyStoreRequest::fake()).unwrap();
+    let fake_reply = request("POST", &submit_url, Some(fake)).await.unwrap();
+    assert_eq!(fake_reply.0, StatusCode::OK);
+    let response: SubmitResponse = serde_json::from_value(fake_reply.1.clone()).unwrap();
+    assert_eq!(response, SubmitResponse::padded());
+    let page = wait_for(&fetch_url, |_| true).await;
+    assert_eq!(key_count(&page), 0);
+
+    let real = DailyTracingKeyStoreRequest::new(random_keys(3)).padded();
+    let real_reply = request(
+        "POST",
+        &submit_url,
+        Some(serde_json::to_value(real).unwrap()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(real_reply, fake_reply);
+    let fake = serde_json::to_value(DailyTracingKeyStoreRequest::fake()).unwrap();
+    let fake_reply_after = request("POST", &submit_url, Some(fake)).await.unwrap();
+    assert_eq!(fake_reply_after, fake_reply);
+
+    // only the real keys were stored
+    let page = wait_for(&fetch_url, |page| key_count(page) >= 3).await;
+    assert_eq!(key_count(&page), 3);
+
+    // unpadded and overpadded submissions are rejected
+    let unpadded = DailyTracingKeyStoreRequest::new(random_keys(2));
+    let mut overpadded = unpadded.clone().padded();
+    overpadded.padding.push('=');
+    for body in &[unpadded, overpadded] {
+        let body = serde_json::to_value(body).unwrap();
+        let (status, _) = request("POST", &submit_url, Some(body)).await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(submit_keys(&instance.url, 2).await, StatusCode::OK);
+
+    // fake submissions are validated like real ones
+    let mut invalid = serde_json::to_value(DailyTracingKeyStoreRequest::fake()).unwrap();
+    invalid["keys"] = serde_json::json!("nope");
+    let (status, _) = request("POST", &submit_url, Some(invalid)).await.unwrap();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let mut invalid = serde_json::to_value(DailyTracingKeyStoreRequest::fake()).unwrap();
+    invalid["padding"] = serde_json::json!("smuggled data");
+    let (status, _) = request("POST", &submit_url, Some(invalid)).await.unwrap();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // keys for days outside of the retention window are never stored
+    let today = day_number_for_timestamp(&chrono::Utc::now());
+    let future_key = DailyTracingKey::for_day(&TracingKey::unique(), today + 1);
+    for fake in &[false, true, false] {
+        let mut request_body = DailyTracingKeyStoreRequest::new(vec![(today + 1, future_key)]);
+        request_body.fake = *fake;
+        let body = serde_json::to_value(request_body.padded()).unwrap();
+        let (status, _) = request("POST", &submit_url, Some(body)).await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let (_, page) = request("GET", &fetch_url, None).await.unwrap();
+    assert_eq!(key_count(&page), 5);
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::client::HttpConnector;
+use hyper::{Client, StatusCode};
+use hyper_rustls::HttpsConnector;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{ClientConfig, Session};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+use common::{free_port, Instance};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/tls")
+        .join(name)
+}
+
+fn load_cert(name: &str) -> Vec<u8> {
+    let mut certs =
+        pemfile::certs(&mut BufReader::new(File::open(fixture(name)).unwrap())).unwrap();
+    certs.remove(0).0
+}
+
+/// Creates a client config that trusts the test CA.
+fn client_config(with_certificate: bool) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_pem_file(&mut BufReader::new(File::open(fixture("ca.pem")).unwrap()))
+        .unwrap();
+    if with_certificate {
+        let certs = pemfile::certs(&mut BufReader::new(
+            File::open(fixture("client.pem")).unwrap(),
+        ))
+        .unwrap();
+        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(
+            File::open(fixture("client.key")).unwrap(),
+        ))
+        .unwrap();
+        config
+            .set_single_client_cert(certs, keys.remove(0))
+            .unwrap();
+    }
+    config
+}
+
+async fn get(url: &str, with_certificate: bool) -> Option<StatusCode> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let client: Client<_> = Client::builder().build(HttpsConnector::from((
+        http,
+        client_config(with_certificate),
+    )));
+    Some(client.get(url.parse().unwrap()).await.ok()?.status())
+}
+
+/// Returns the certificate the server presents.
+async fn server_cert(port: u16) -> Vec<u8> {
+    let tcp = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+        .await
+        .unwrap();
+    let connector = TlsConnector::from(Arc::new(client_config(false)));
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let stream = connector.connect(domain, tcp).await.unwrap();
+    stream.get_ref().1.get_peer_certificates().unwrap()[0]
+        .0
+        .clone()
+}
+
+fn start_tls_instance(dir: &Path, port: u16) -> Instance {
+    fs::copy(fixture("server.pem"), dir.join("server.pem")).unwrap();
+    fs::copy(fixture("server.key"), dir.join("server.key")).unwrap();
+    Instance::start(
+        dir,
+        "backend",
+        port,
+        &format!(
+            "tls:\n  cert_path: {}\n  key_path: {}\n  client_ca_path: {}\n",
+            dir.join("server.pem").display(),
+            dir.join("server.key").display(),
+            fixture("ca.pem").display()
+        ),
+    )
+}
+
+async fn wait_for_tls(url: &str) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while get(url, false).await != Some(StatusCode::OK) {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", url);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_tls_client_certificates() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let _instance = start_tls_instance(dir.path(), port);
+    let url = format!("https://localhost:{}", port);
+    wait_for_tls(&format!("{}/healthz", url)).await;
+
+    // public endpoints do not need a client certificate
+    assert_eq!(
+        get(&format!("{}/days", url), false).await,
+        Some(StatusCode::OK)
+    );
+
+    // admin and federation endpoints do
+    for path in &[
+        "/metrics",
+        "/replication/status",
+        "/federation/batches?since=0",
+    ] {
+        let endpoint = format!("{}{}", url, path);
+        assert_eq!(get(&endpoint, false).await, Some(StatusCode::FORBIDDEN));
+        assert_ne!(get(&endpoint, true).await, Some(StatusCode::FORBIDDEN));
+    }
+    assert_eq!(
+        get(&format!("{}/metrics", url), true).await,
+        Some(StatusCode::OK)
+    );
+
+    // plain HTTP is not served
+    assert!(
+        common::request("GET", &format!("http://127.0.0.1:{}/healthz", port), None)
+            .await
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn test_certificate_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    let instance = start_tls_instance(dir.path(), port);
+    wait_for_tls(&format!("https://localhost:{}/healthz", port)).await;
+    assert_eq!(server_cert(port).await, load_cert("server.pem"));
+
+    fs::copy(fixture("server2.pem"), dir.path().join("server.pem")).unwrap();
+    fs::copy(fixture("server2.key"), dir.path().join("server.key")).unwrap();
+    instance.signal("HUP");
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while server_cert(port).await != load_cert("server2.pem") {
+        assert!(Instant::now() < deadline, "certificate was not reloaded");
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+
+    // a broken certificate keeps the old one
+    fs::write(dir.path().join("server.pem"), "garbage").unwrap();
+    instance.signal("HUP");
+    tokio::time::delay_for(Duration::from_millis(500)).await;
+    assert_eq!(server_cert(port).await, load_cert("server2.pem"));
+}
@@ -34,7 +34,7 @@ impl DailyTracingKey {
     /// Returns the daily tracing key for a day.
     pub fn for_day(tk: &TracingKey, day: u32) -> DailyTracingKey {
         let h = Hkdf::<Sha256>::new(None, tk.as_bytes());
-        let mut info = BytesMut::from(&"CT-DTK"[..]);
+        let mut info = BytesMut::from("CT-DTK");
         info.put_u32_le(day);
         let mut out = [0u8; 16];
         h.expand(&info, &mut out).unwrap();
@@ -75,9 +75,8 @@ impl DailyTracingKey {
         let clone = *self;
         let mut tin = 0;
         std::iter::from_fn(move || {
-            clone.get_rpi_for_tin(tin).map(|rv| {
+            clone.get_rpi_for_tin(tin).inspect(|_| {
                 tin += 1;
-                rv
             })
         })
     }
@@ -90,8 +89,8 @@ impl DailyTracingKey {
             return None;
         }
 
-        let mut hmac = Hmac::<Sha256>::new_varkey(&self.as_bytes()).unwrap();
-        let mut info = BytesMut::from(&"CT-RPI"[..]);
+        let mut hmac = Hmac::<Sha256>::new_varkey(self.as_bytes()).unwrap();
+        let mut info = BytesMut::from("CT-RPI");
         info.put_u8(tin);
         hmac.input(&info[..]);
         let result = hmac.result();
@@ -165,7 +164,7 @@ mod serde_impl {
                 let s = String::deserialize(deserializer).map_err(D::Error::custom)?;
                 s.parse().map_err(D::Error::custom)
             } else {
-                let buf = Vec::<u8>::deserialize(deserializer).map_err(D::Error::custom)?;
+                let buf = crate::utils::deserialize_bytes(deserializer)?;
                 DailyTracingKey::from_bytes(&buf).map_err(D::Error::custom)
             }
         }
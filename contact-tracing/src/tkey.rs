@@ -108,7 +108,7 @@ mod serde_impl {
                 let s = String::deserialize(deserializer).map_err(D::Error::custom)?;
                 s.parse().map_err(D::Error::custom)
             } else {
-                let buf = Vec::<u8>::deserialize(deserializer).map_err(D::Error::custom)?;
+                let buf = crate::utils::deserialize_bytes(deserializer)?;
                 TracingKey::from_bytes(&buf).map_err(D::Error::custom)
             }
         }
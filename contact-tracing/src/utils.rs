@@ -19,6 +19,47 @@ impl<'a, T: fmt::Debug> fmt::Debug for Base64DebugFmtHelper<'a, T> {
     }
 }
 
+/// Deserializes raw bytes from binary formats.
+///
+/// Formats like CBOR and MessagePack hand out byte strings which `Vec<u8>`
+/// does not accept on its own, so this accepts both bytes and sequences.
+#[cfg(feature = "serde")]
+pub(crate) fn deserialize_bytes<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: serde_::de::Deserializer<'de>,
+{
+    struct BytesVisitor;
+
+    impl<'de> serde_::de::Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("bytes")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Vec<u8>, A::Error>
+        where
+            A: serde_::de::SeqAccess<'de>,
+        {
+            let mut rv = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                rv.push(byte);
+            }
+            Ok(rv)
+        }
+    }
+
+    deserializer.deserialize_bytes(BytesVisitor)
+}
+
 /// Returns the day number for a timestamp.
 #[cfg(feature = "chrono")]
 pub fn day_number_for_timestamp(ts: &DateTime<Utc>) -> u32 {
@@ -34,7 +75,7 @@ pub fn tin_for_timestamp_checked(ts: &DateTime<Utc>, day: Date<Utc>) -> Option<u
     let now = ts.timestamp();
     let start_of_day = day.and_hms(0, 0, 0).timestamp();
     let tin = (now - start_of_day) / (60 * 10);
-    if tin >= 0 && tin <= 143 {
+    if (0..=143).contains(&tin) {
         Some(tin as u8)
     } else {
         None